@@ -1,272 +1,5473 @@
 #[allow(warnings)]
 mod bindings;
 use serde_json::Value as JsonValue;
+use std::collections::{HashMap, HashSet};
 
 use bindings::{
     exports::supabase::wrappers::routines::Guest,
-    supabase::wrappers::{
-        http, time,
-        types::{Cell, Context, FdwError, FdwResult, OptionsType, Row, TypeOid},
-        utils,
+    supabase::wrappers::types::{
+        Cell, Context, FdwError, FdwResult, Options, OptionsType, Row, TypeOid, Value,
     },
 };
+// host interfaces, which tests swap for fakes as they only exist in the
+// wasm runtime
+#[cfg(not(test))]
+use bindings::supabase::wrappers::{http, stats, time, utils};
+#[cfg(test)]
+use tests::host::{http, stats, time, utils};
+
+// how records are laid out at the results path of a response
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ResultsShape {
+    // detect from the response
+    Auto,
+    Array,
+    // an object of records keyed by id, as returned by some batch endpoints
+    Map,
+}
+
+impl std::str::FromStr for ResultsShape {
+    type Err = FdwError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "array" => Ok(Self::Array),
+            "map" => Ok(Self::Map),
+            _ => Err(format!("unsupported results shape '{}'", s)),
+        }
+    }
+}
+
+// describes how a HubSpot object type is read
+#[derive(Debug)]
+struct ObjectSpec {
+    // API endpoint path, relative to the base URL
+    endpoint: String,
+    method: http::Method,
+    // request body for POST reads, which also carries the paging cursor
+    body: Option<JsonValue>,
+    // properties to request, for endpoints which take a `properties` parameter
+    properties: Vec<String>,
+    // raw query parameters appended to every request
+    extra_params: Option<String>,
+    // dotted path to the records in the response body
+    results_path: String,
+    results_shape: ResultsShape,
+    // largest page size, for endpoints which take a `limit` parameter
+    max_page_size: Option<u32>,
+    // whether this is a CRM object read through the objects API
+    crm: bool,
+    // whether the records are revisions derived from a single record's
+    // property history
+    revisions: bool,
+    // properties holding when a CRM record was created and last modified,
+    // which the original objects name differently from later ones
+    created_property: &'static str,
+    modified_property: &'static str,
+}
+
+// largest page accepted by the CRM search endpoint
+const SEARCH_MAX_PAGE_SIZE: u32 = 200;
+
+// most filters the CRM search endpoint takes in a filter group, and most
+// values it takes for an IN filter
+const SEARCH_MAX_FILTERS: usize = 6;
+const SEARCH_MAX_FILTER_VALUES: usize = 100;
+
+// most results the CRM search endpoint pages through for a single query
+const SEARCH_RESULT_LIMIT: u64 = 10_000;
+
+// exclusive end of the first search window, in epoch millis (2100-01-01),
+// fixed rather than taken from the clock so every scan splits at the same
+// points and comes back in the same order
+const SEARCH_WINDOW_END_MS: i64 = 4_102_444_800_000;
+
+// largest page accepted by the CRM list endpoint when history is requested
+const HISTORY_MAX_PAGE_SIZE: u32 = 50;
+
+impl Default for ObjectSpec {
+    fn default() -> Self {
+        Self {
+            endpoint: String::default(),
+            method: http::Method::Get,
+            body: None,
+            properties: Vec::new(),
+            extra_params: None,
+            results_path: "results".to_owned(),
+            results_shape: ResultsShape::Auto,
+            max_page_size: None,
+            crm: false,
+            revisions: false,
+            created_property: "hs_createdate",
+            modified_property: "hs_lastmodifieddate",
+        }
+    }
+}
+
+// object types with built-in support
+const BUILTIN_OBJECTS: &[&str] = &[
+    "contacts",
+    "companies",
+    "deals",
+    "calls",
+    "emails",
+    "meetings",
+    "notes",
+    "tasks",
+    "subscriptions",
+    "forms",
+    "custom",
+    "revisions",
+];
+
+impl ObjectSpec {
+    // the largest page accepted by the endpoint being read, if it takes a
+    // `limit` at all
+    fn page_size_cap(&self, search: bool, with_history: bool) -> Option<u32> {
+        if search {
+            Some(SEARCH_MAX_PAGE_SIZE)
+        } else if with_history {
+            self.max_page_size.map(|max| max.min(HISTORY_MAX_PAGE_SIZE))
+        } else {
+            self.max_page_size
+        }
+    }
+
+    // `object_schemas` is the portal's object type list, when it has been
+    // fetched for validation
+    fn new(
+        object: &str,
+        opts: &impl OptionSource,
+        object_schemas: Option<&[String]>,
+    ) -> Result<Self, FdwError> {
+        let crm_object = |properties: &[&str]| Self {
+            endpoint: format!("/crm/v3/objects/{}", object),
+            properties: properties.iter().map(|p| p.to_string()).collect(),
+            max_page_size: Some(100),
+            crm: true,
+            ..Default::default()
+        };
+
+        match object {
+            "contacts" => Ok(Self {
+                created_property: "createdate",
+                modified_property: "lastmodifieddate",
+                ..crm_object(&["firstName", "lastName", "email", "phone"])
+            }),
+            "companies" => Ok(Self {
+                created_property: "createdate",
+                ..crm_object(&["name", "domain", "industry", "city", "phone"])
+            }),
+            "deals" => Ok(Self {
+                created_property: "createdate",
+                ..crm_object(&["amount", "dealstage", "dealname", "closedate"])
+            }),
+            // engagements, which share the objects API
+            "calls" => Ok(crm_object(&["hs_timestamp", "hs_call_title", "hs_call_direction"])),
+            "emails" => Ok(crm_object(&["hs_timestamp", "hs_email_subject", "hs_email_direction"])),
+            "meetings" => Ok(crm_object(&[
+                "hs_timestamp",
+                "hs_meeting_title",
+                "hs_meeting_outcome",
+            ])),
+            "notes" => Ok(crm_object(&["hs_timestamp", "hs_note_body"])),
+            "tasks" => Ok(crm_object(&["hs_timestamp", "hs_task_subject", "hs_task_status"])),
+            "subscriptions" => {
+                // subscription statuses are read per contact, so there is
+                // only ever a single page of them
+                let email = opts.require("contact_email")?;
+                Ok(Self {
+                    endpoint: format!(
+                        "/communication-preferences/v3/status/email/{}",
+                        url_encode(&email)
+                    ),
+                    results_path: "subscriptionStatuses".to_owned(),
+                    ..Default::default()
+                })
+            }
+            "forms" => Ok(Self {
+                endpoint: "/marketing/v3/forms".to_owned(),
+                ..Default::default()
+            }),
+            "revisions" => {
+                // a record's property history, grouped into revisions
+                let revisions_object = opts.require("revisions_object")?;
+                let record_id = opts.require("record_id")?;
+                Ok(Self {
+                    endpoint: format!(
+                        "/crm/v3/objects/{}/{}",
+                        url_encode(&revisions_object),
+                        url_encode(&record_id)
+                    ),
+                    revisions: true,
+                    ..Default::default()
+                })
+            }
+            "custom" => {
+                // escape hatch for list-shaped endpoints not modelled here
+                let endpoint = match opts.get("endpoint_template") {
+                    Some(template) => render_endpoint_template(&template, object, opts)?,
+                    None => opts.require("endpoint")?,
+                };
+                if !endpoint.starts_with('/') {
+                    return Err(format!("endpoint '{}' must start with '/'", endpoint));
+                }
+                let (method, body) = match opts.require_or("request_method", "get").as_str() {
+                    "get" => (http::Method::Get, None),
+                    "post" => {
+                        let body = opts.require_or("request_body", "{}");
+                        let body: JsonValue = serde_json::from_str(&body)
+                            .map_err(|e| format!("request_body is not valid JSON: {}", e))?;
+                        if !body.is_object() {
+                            return Err("request_body must be a JSON object".to_string());
+                        }
+                        (http::Method::Post, Some(body))
+                    }
+                    method => return Err(format!("unsupported request_method '{}'", method)),
+                };
+                Ok(Self {
+                    endpoint,
+                    method,
+                    body,
+                    ..Default::default()
+                })
+            }
+            _ => match object_schemas {
+                Some(schemas) if schemas.iter().any(|s| s == object) => Ok(crm_object(&[])),
+                Some(schemas) => {
+                    let valid_types: Vec<&str> = BUILTIN_OBJECTS
+                        .iter()
+                        .copied()
+                        .chain(schemas.iter().map(String::as_str))
+                        .collect();
+                    Err(format!(
+                        "Unknown object type '{}', valid types are: {}",
+                        object,
+                        valid_types.join(", ")
+                    ))
+                }
+                None if is_custom_object_type(object) => Ok(crm_object(&[])),
+                None => Err(format!("Unsupported object type: {}", object)),
+            },
+        }
+    }
+}
+
+// a row's cells, along with the columns which failed to convert and why
+type ConvertedRow = (Vec<Option<Cell>>, Vec<(String, String)>);
+
+// what to do with a value which can't be converted to its column's type
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ConversionErrorMode {
+    // abort the scan
+    Error,
+    // emit NULL for the column
+    Null,
+    // leave the row out of the results
+    SkipRow,
+}
+
+impl std::str::FromStr for ConversionErrorMode {
+    type Err = FdwError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Self::Error),
+            "null" => Ok(Self::Null),
+            "skip_row" => Ok(Self::SkipRow),
+            _ => Err(format!("unsupported conversion error mode '{}'", s)),
+        }
+    }
+}
+
+// alternative interpretations of a property value for a specific column
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ValueKind {
+    // timestamp-shaped property emitted as epoch milliseconds
+    EpochMillisOut,
+    // duration in milliseconds, like `hs_time_in_<stage>`, emitted as
+    // whole seconds
+    MillisToSecs,
+}
+
+impl std::str::FromStr for ValueKind {
+    type Err = FdwError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "epoch_millis_out" => Ok(Self::EpochMillisOut),
+            "millis_to_secs" => Ok(Self::MillisToSecs),
+            _ => Err(format!("unsupported value kind '{}'", s)),
+        }
+    }
+}
+
+// whether rows are HubSpot's JSON as returned, for troubleshooting
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RawMode {
+    Off,
+    // each row is a record
+    Record,
+    // each row is a whole page, envelope included
+    Page,
+}
+
+impl std::str::FromStr for RawMode {
+    type Err = FdwError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "false" => Ok(Self::Off),
+            "true" | "record" => Ok(Self::Record),
+            "page" => Ok(Self::Page),
+            _ => Err(format!("unsupported raw mode '{}'", s)),
+        }
+    }
+}
+
+// how changes in the properties present on later pages are reported
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SchemaDriftMode {
+    Warn,
+    Error,
+}
+
+impl std::str::FromStr for SchemaDriftMode {
+    type Err = FdwError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "warn" => Ok(Self::Warn),
+            "error" => Ok(Self::Error),
+            _ => Err(format!("unsupported schema drift mode '{}'", s)),
+        }
+    }
+}
+
+// normalizations applied to a text column's values
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Normalization {
+    // phone numbers in E.164 format, like `+15551234567`
+    PhoneE164,
+}
+
+impl std::str::FromStr for Normalization {
+    type Err = FdwError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "phone_e164" => Ok(Self::PhoneE164),
+            _ => Err(format!("unsupported normalization '{}'", s)),
+        }
+    }
+}
+
+// how a boolean property reads in a text column
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BoolTextFormat {
+    YesNo,
+    TrueFalse,
+    OneZero,
+}
+
+impl BoolTextFormat {
+    fn format(self, value: bool) -> &'static str {
+        match (self, value) {
+            (Self::YesNo, true) => "Yes",
+            (Self::YesNo, false) => "No",
+            (Self::TrueFalse, true) => "true",
+            (Self::TrueFalse, false) => "false",
+            (Self::OneZero, true) => "1",
+            (Self::OneZero, false) => "0",
+        }
+    }
+}
+
+impl std::str::FromStr for BoolTextFormat {
+    type Err = FdwError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "yes_no" => Ok(Self::YesNo),
+            "true_false" => Ok(Self::TrueFalse),
+            "1_0" => Ok(Self::OneZero),
+            _ => Err(format!("unsupported bool text format '{}'", s)),
+        }
+    }
+}
+
+// credentials for refreshing an expired OAuth access token
+#[derive(Debug)]
+struct OAuthRefresh {
+    refresh_token: String,
+    client_id: String,
+    client_secret: String,
+}
 
 #[derive(Debug)]
 struct HubspotFdw {
     api_key: String,
     base_url: String,
+    // the server's `api_url`, which tables can override with their own
+    // when the server allows it
+    server_base_url: String,
+    allow_table_api_url: bool,
+    oauth_refresh: Option<OAuthRefresh>,
+    // when the access token expires, in epoch seconds, if known
+    token_expires_at: Option<i64>,
+    persist_refreshed_token: bool,
+    object: String,
+    // all the table's objects, and those still to be scanned in reverse
+    objects: Vec<String>,
+    pending_objects: Vec<String>,
+    // how errors and logs refer to the table's object, naming the table's
+    // `object_alias` when several tables share an object
+    object_label: String,
+    object_spec: ObjectSpec,
+    max_retries: u32,
+    retry_on_5xx: bool,
+    retry_status_codes: Vec<u16>,
+    // statuses retried while modifying, where only failures which surely
+    // left the record untouched are safe to repeat
+    write_retry_status_codes: Vec<u16>,
+    in_modify: bool,
+    debug_plan: bool,
+    scan_deadline_ms: Option<i64>,
+    rate_limit_per_sec: Option<u32>,
+    // when the current scan began, in epoch millis
+    scan_started_ms: Option<i64>,
+    request_seq: u64,
+    fetch_seq: u64,
     src_rows: Vec<JsonValue>,
     src_idx: usize,
     after: Option<String>,
     has_more: bool,
+    json_sort_keys: bool,
+    preserve_number_text: bool,
+    // match columns against property keys regardless of case
+    lowercase_property_keys: bool,
+    json_null_literal: bool,
+    skip_incomplete_rows: bool,
+    required_properties: Vec<String>,
+    skipped_rows: usize,
+    // cap on the conversion warnings logged per scan, and how many were
+    // logged and held back so far
+    error_sample_limit: Option<usize>,
+    warnings_logged: usize,
+    warnings_suppressed: usize,
+    value_kinds: HashMap<String, ValueKind>,
+    // columns reading a differently named property
+    property_map: HashMap<String, String>,
+    bool_text_formats: HashMap<String, BoolTextFormat>,
+    normalizations: HashMap<String, Normalization>,
+    object_schemas: Option<Vec<String>>,
+    history_properties: Vec<String>,
+    history_limit: Option<usize>,
+    id_only: bool,
+    enum_labels: HashMap<String, HashMap<String, String>>,
+    use_search: bool,
+    auto_search_fallback: bool,
+    // properties the selected columns read, leaving out the defaults
+    // fetched when none of them is a property
+    selected_properties: Vec<String>,
+    // set once a scan gives up on search for the list endpoint
+    search_abandoned: bool,
+    // body of the last error response, when it was JSON
+    last_error: Option<JsonValue>,
+    owner_id: Option<String>,
+    // search filters translated from the scan's quals
+    qual_filters: Vec<JsonValue>,
+    stable_order: bool,
+    modified_since: Option<i64>,
+    // change-data-capture watermark, in epoch micros
+    cdc_watermark: Option<i64>,
+    on_conversion_error: ConversionErrorMode,
+    association_targets: Vec<String>,
+    unnest_target: Option<String>,
+    fetch_association_labels: bool,
+    schema_drift: Option<SchemaDriftMode>,
+    // selected properties present on the scan's first non-empty page
+    first_page_properties: Option<HashSet<String>>,
+    association_properties: Vec<String>,
+    max_associations: usize,
+    fetched_at: i64,
+    search_total: Option<i64>,
+    pages_fetched: usize,
+    // consecutive empty pages which still had a next page
+    empty_pages: u32,
+    max_empty_pages: u32,
+    page_size: Option<u32>,
+    partition_property: String,
+    search_window: Option<(i64, i64)>,
+    pending_windows: Vec<(i64, i64)>,
+    seen_ids: HashSet<String>,
+    emit_keyset: bool,
+    page_cursor: Option<String>,
+    last_id: Option<String>,
+    last_updated_at: Option<String>,
+    rows_emitted: u64,
+    // the scan's selected columns
+    columns: Vec<ColumnSpec>,
+    multi_value_columns: Vec<String>,
+    raw_mode: RawMode,
+    // the scan can't return rows, so it makes no requests
+    short_circuited: bool,
+    // jsonb column gathering the record's source attribution properties
+    provenance_column: Option<String>,
+    // write operations the table explicitly allows
+    write_operations: Vec<String>,
 }
 
+// consecutive empty pages with a next page tolerated unless told otherwise
+const DEFAULT_MAX_EMPTY_PAGES: u32 = 2;
+
+// most associated records a batch read takes
+const ASSOCIATION_BATCH_SIZE: usize = 100;
+
+// most associated records unnested per record unless told otherwise
+const DEFAULT_MAX_ASSOCIATIONS: usize = 100;
+
+// response statuses retried unless `http_retry_status_codes` says otherwise
+const DEFAULT_RETRY_STATUS_CODES: &[u16] = &[429, 500, 502, 503, 504];
+
+// statuses retried while modifying unless `write_retry_status_codes` says
+// otherwise. A server error may come after the write went through
+const DEFAULT_WRITE_RETRY_STATUS_CODES: &[u16] = &[429];
+
+// name the FDW's stats and metadata are recorded under
+const FDW_NAME: &str = "HubspotFdw";
+
 // pointer for the static FDW instance
 static mut INSTANCE: *mut HubspotFdw = std::ptr::null_mut::<HubspotFdw>();
 
+// token bucket refilled at the start of every second, shared by all scans
+// so they cooperatively stay within the portal-wide rate limit
+#[derive(Debug, Default)]
+struct RateLimiter {
+    // the second the bucket was last refilled, in epoch seconds
+    refilled_at: i64,
+    tokens: u32,
+}
+
+impl RateLimiter {
+    // take a token at `now`, in epoch seconds, refilling the bucket with
+    // `rate_limit` tokens when a new second has started
+    fn try_take(&mut self, now: i64, rate_limit: u32) -> bool {
+        if now != self.refilled_at {
+            self.refilled_at = now;
+            self.tokens = rate_limit;
+        }
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+}
+
+// pointer for the shared rate limiter, kept apart from the FDW instance so
+// it outlives re-initialization
+static mut RATE_LIMITER: *mut RateLimiter = std::ptr::null_mut::<RateLimiter>();
+
+// pointer for the object schema lists fetched by validate_on_init, keyed by
+// server fingerprint and likewise kept apart from the FDW instance, so the
+// list is fetched once per server rather than on every init. Object types
+// created in the portal afterwards show up once the module is reloaded
+static mut OBJECT_SCHEMAS: *mut HashMap<u64, Vec<String>> =
+    std::ptr::null_mut::<HashMap<u64, Vec<String>>>();
+
 impl Default for HubspotFdw {
     fn default() -> Self {
         Self {
             api_key: String::default(),
             base_url: "https://api.hubapi.com".to_string(),
+            server_base_url: "https://api.hubapi.com".to_string(),
+            allow_table_api_url: false,
+            oauth_refresh: None,
+            token_expires_at: None,
+            persist_refreshed_token: false,
+            object: String::default(),
+            objects: Vec::new(),
+            pending_objects: Vec::new(),
+            object_label: String::default(),
+            object_spec: ObjectSpec::default(),
+            max_retries: 3,
+            retry_on_5xx: true,
+            retry_status_codes: DEFAULT_RETRY_STATUS_CODES.to_vec(),
+            write_retry_status_codes: DEFAULT_WRITE_RETRY_STATUS_CODES.to_vec(),
+            in_modify: false,
+            debug_plan: false,
+            scan_deadline_ms: None,
+            rate_limit_per_sec: None,
+            scan_started_ms: None,
+            request_seq: 0,
+            fetch_seq: 0,
             src_rows: Vec::new(),
             src_idx: 0,
             after: None,
             has_more: false,
+            json_sort_keys: false,
+            preserve_number_text: false,
+            lowercase_property_keys: false,
+            json_null_literal: false,
+            skip_incomplete_rows: false,
+            required_properties: Vec::new(),
+            skipped_rows: 0,
+            error_sample_limit: None,
+            warnings_logged: 0,
+            warnings_suppressed: 0,
+            value_kinds: HashMap::new(),
+            property_map: HashMap::new(),
+            bool_text_formats: HashMap::new(),
+            normalizations: HashMap::new(),
+            object_schemas: None,
+            history_properties: Vec::new(),
+            history_limit: None,
+            id_only: false,
+            enum_labels: HashMap::new(),
+            use_search: false,
+            auto_search_fallback: false,
+            selected_properties: Vec::new(),
+            search_abandoned: false,
+            last_error: None,
+            owner_id: None,
+            qual_filters: Vec::new(),
+            stable_order: false,
+            modified_since: None,
+            cdc_watermark: None,
+            on_conversion_error: ConversionErrorMode::Error,
+            association_targets: Vec::new(),
+            unnest_target: None,
+            fetch_association_labels: false,
+            schema_drift: None,
+            first_page_properties: None,
+            association_properties: Vec::new(),
+            max_associations: DEFAULT_MAX_ASSOCIATIONS,
+            fetched_at: 0,
+            search_total: None,
+            pages_fetched: 0,
+            empty_pages: 0,
+            max_empty_pages: DEFAULT_MAX_EMPTY_PAGES,
+            page_size: None,
+            partition_property: "createdate".to_owned(),
+            search_window: None,
+            pending_windows: Vec::new(),
+            seen_ids: HashSet::new(),
+            emit_keyset: false,
+            page_cursor: None,
+            last_id: None,
+            last_updated_at: None,
+            rows_emitted: 0,
+            columns: Vec::new(),
+            multi_value_columns: Vec::new(),
+            raw_mode: RawMode::Off,
+            short_circuited: false,
+            provenance_column: None,
+            write_operations: Vec::new(),
         }
     }
 }
 
-impl HubspotFdw {
-    // initialise FDW instance
-    fn init_instance() {
-        let instance = Self::default();
-        unsafe {
-            INSTANCE = Box::leak(Box::new(instance));
-        }
+// longest request URL to send, beyond which HubSpot may reject the request
+const MAX_URL_LENGTH: usize = 2000;
+
+// the `hs_object_source*` properties telling where a record came from
+const SOURCE_PROPERTIES: &[&str] = &[
+    "hs_object_source",
+    "hs_object_source_id",
+    "hs_object_source_label",
+    "hs_object_source_user_id",
+    "hs_object_source_detail_1",
+    "hs_object_source_detail_2",
+    "hs_object_source_detail_3",
+];
+
+// columns synthesized by the FDW rather than read from records
+const PSEUDO_COLUMNS: &[&str] = &[
+    "_errors",
+    "_fetched_at",
+    "_total",
+    "_has_more",
+    "_op",
+    "_object_type",
+    "_merge_into",
+    "association_label",
+];
+
+// record keys returned by the CRM objects API alongside `properties`
+const TOP_LEVEL_KEYS: &[&str] = &[
+    "id",
+    "createdAt",
+    "updatedAt",
+    "archived",
+    "archivedAt",
+    "properties",
+    "propertiesWithHistory",
+    "associations",
+];
+
+// base delay before the first retry of a failed request
+const RETRY_BASE_DELAY_MS: u64 = 1000;
+
+// server or table options, read from the host or, in tests, from a map
+trait OptionSource {
+    fn get(&self, key: &str) -> Option<String>;
+
+    fn require(&self, key: &str) -> Result<String, FdwError> {
+        self.get(key)
+            .ok_or_else(|| format!("required option '{}' is not specified", key))
     }
 
-    fn this_mut() -> &'static mut Self {
-        unsafe { &mut (*INSTANCE) }
+    fn require_or(&self, key: &str, default: &str) -> String {
+        self.get(key).unwrap_or_else(|| default.to_owned())
     }
+}
 
-    fn fetch_data(&mut self, object: &str) -> Result<(), String> {
-        let (endpoint, properties) = match object {
-            "contacts" => ("/crm/v3/objects/contacts", "firstName,lastName,email,phone"),
-            "companies" => ("/crm/v3/objects/companies", "name,domain,industry,city,phone"),
-            "deals" => ("/crm/v3/objects/deals", "amount,dealstage,dealname,closedate"),
-            _ => return Err(format!("Unsupported object type: {}", object)),
-        };
+impl OptionSource for Options {
+    fn get(&self, key: &str) -> Option<String> {
+        Options::get(self, key)
+    }
 
-        let mut url = format!("{}{}", self.base_url, endpoint);
+    fn require(&self, key: &str) -> Result<String, FdwError> {
+        Options::require(self, key)
+    }
 
-        // Add query parameters
-        url.push_str("?limit=100");
-        url.push_str(&format!("&properties={}", properties));
-        if let Some(after) = &self.after {
-            url.push_str(&format!("&after={}", after));
-        }
+    fn require_or(&self, key: &str, default: &str) -> String {
+        Options::require_or(self, key, default)
+    }
+}
 
-        let headers = vec![
-            ("authorization".to_owned(), format!("Bearer {}", self.api_key)),
-            ("content-type".to_owned(), "application/json".to_owned()),
-        ];
+// a selected column's name and type
+#[derive(Debug, Clone)]
+struct ColumnSpec {
+    name: String,
+    type_oid: TypeOid,
+}
 
-        let req = http::Request {
-            method: http::Method::Get,
-            url,
-            headers,
-            body: String::default(),
-        };
+// a qual Postgres passes down with the scan
+#[derive(Debug, Clone)]
+struct QualSpec {
+    field: String,
+    operator: String,
+    value: Value,
+    use_or: bool,
+    // whether the value is a query parameter, only known at execution
+    has_param: bool,
+}
 
-        let resp = http::get(&req).map_err(|e| e.to_string())?;
-        let resp_json: JsonValue = serde_json::from_str(&resp.body).map_err(|e| e.to_string())?;
+// what a scan reads from the host's context, so the scan itself can run
+// against plain values in tests
+trait ScanSource {
+    type Options: OptionSource;
 
-        // Extract pagination info
-        if let Some(paging) = resp_json.get("paging") {
-            self.has_more = paging.get("next").is_some();
-            if self.has_more {
-                self.after = paging.get("next").and_then(|v| v.get("after")).and_then(|v| v.as_str()).map(String::from);
-            }
-        }
+    fn table_options(&self) -> Self::Options;
+    fn columns(&self) -> Vec<ColumnSpec>;
+    fn quals(&self) -> Vec<QualSpec>;
+    fn limit(&self) -> Option<i64>;
+}
 
-        // Extract results
-        if let Some(results) = resp_json.get("results") {
-            if let Some(array) = results.as_array() {
-                self.src_rows.extend(array.iter().cloned());
-            }
-        }
+impl ScanSource for Context {
+    type Options = Options;
 
-        Ok(())
+    fn table_options(&self) -> Options {
+        self.get_options(OptionsType::Table)
     }
-}
 
-impl Guest for HubspotFdw {
-    fn host_version_requirement() -> String {
-        "^0.1.0".to_string()
+    fn columns(&self) -> Vec<ColumnSpec> {
+        self.get_columns()
+            .iter()
+            .map(|col| ColumnSpec {
+                name: col.name(),
+                type_oid: col.type_oid(),
+            })
+            .collect()
     }
 
-    fn init(ctx: &Context) -> FdwResult {
-        Self::init_instance();
-        let this = Self::this_mut();
+    fn quals(&self) -> Vec<QualSpec> {
+        self.get_quals()
+            .iter()
+            .map(|qual| QualSpec {
+                field: qual.field(),
+                operator: qual.operator(),
+                value: qual.value(),
+                use_or: qual.use_or(),
+                has_param: qual.param().is_some(),
+            })
+            .collect()
+    }
 
-        let opts = ctx.get_options(OptionsType::Server);
-        this.api_key = opts.require("api_key")?;
+    fn limit(&self) -> Option<i64> {
+        self.get_limit().map(|limit| limit.count())
+    }
+}
 
-        Ok(())
+// parse an optional option value, falling back to a default when not set
+fn parse_option<T: std::str::FromStr>(
+    opts: &impl OptionSource,
+    key: &str,
+    default: T,
+) -> Result<T, FdwError> {
+    match opts.get(key) {
+        Some(value) => value
+            .parse()
+            .map_err(|_| format!("invalid value '{}' for option '{}'", value, key)),
+        None => Ok(default),
     }
+}
 
-    fn begin_scan(ctx: &Context) -> FdwResult {
-        let this = Self::this_mut();
-        this.src_rows.clear();
-        this.src_idx = 0;
-        this.after = None;
-        this.has_more = false;
+// parse an `api_url` option, an http(s) URL without the trailing slash
+fn parse_api_url(opts: &impl OptionSource) -> Result<Option<String>, FdwError> {
+    let Some(url) = opts.get("api_url") else {
+        return Ok(None);
+    };
+    if !url.starts_with("https://") && !url.starts_with("http://") {
+        return Err(format!("api_url '{}' must be an http or https URL", url));
+    }
+    Ok(Some(url.trim_end_matches('/').to_owned()))
+}
 
-        let opts = ctx.get_options(OptionsType::Table);
-        let object = opts.require("object")?;
+// parse a comma-separated list option
+fn parse_list(opts: &impl OptionSource, key: &str) -> Vec<String> {
+    opts.require_or(key, "")
+        .split(',')
+        .map(|item| item.trim().to_owned())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
 
-        this.fetch_data(&object)?;
-        utils::report_info(&format!("Initial fetch complete. Row count: {}", this.src_rows.len()));
+// table options which would silently cancel each other, and why
+const CONFLICTING_OPTIONS: &[(&str, &str, &str)] = &[
+    ("raw_mode", "id_only", "raw rows carry whatever HubSpot returns"),
+    ("raw_mode", "emit_keyset", "raw rows aren't tracked by id"),
+    ("raw_mode", "skip_incomplete_rows", "raw rows aren't checked for properties"),
+    ("raw_mode", "lowercase_property_keys", "raw rows keep HubSpot's keys"),
+    ("raw_mode", "provenance_column", "raw rows have no derived columns"),
+    ("raw_mode", "multi_value_columns", "raw rows aren't converted"),
+    ("raw_mode", "hs_value_kind", "raw rows aren't converted"),
+    ("raw_mode", "normalize", "raw rows aren't converted"),
+    ("id_only", "properties_with_history", "id_only scans fetch only ids"),
+    ("id_only", "enum_labels", "id_only scans fetch only ids"),
+];
 
-        Ok(())
+// reject tables combining options which don't work together, an option
+// counting as set unless it's 'false'. Conflicts which depend on the
+// endpoint are checked once it has been chosen
+fn check_option_conflicts(opts: &impl OptionSource) -> Result<(), FdwError> {
+    let set = |name: &str| opts.get(name).is_some_and(|v| v != "false");
+    match CONFLICTING_OPTIONS.iter().find(|(a, b, _)| set(a) && set(b)) {
+        Some((a, b, reason)) => {
+            Err(format!("options '{}' and '{}' can't be combined: {}", a, b, reason))
+        }
+        None => Ok(()),
     }
+}
 
-    fn iter_scan(ctx: &Context, row: &Row) -> Result<Option<u32>, FdwError> {
-        let this = Self::this_mut();
+// parse a `column:value,column:value` option into a map keyed by column name
+fn parse_column_map(
+    opts: &impl OptionSource,
+    key: &str,
+) -> Result<HashMap<String, String>, FdwError> {
+    let mut map = HashMap::new();
+    for entry in opts.require_or(key, "").split(',') {
+        let entry = entry.trim();
+        if entry.is_empty() {
+            continue;
+        }
+        let (column, value) = entry.split_once(':').ok_or(format!(
+            "invalid entry '{}' in option '{}', expected 'column:value'",
+            entry, key
+        ))?;
+        map.insert(column.trim().to_owned(), value.trim().to_owned());
+    }
+    Ok(map)
+}
 
-        if this.src_idx >= this.src_rows.len() {
-            // If we have more data to fetch, get the next page
-            if this.has_more {
-                let opts = ctx.get_options(OptionsType::Table);
-                let object = opts.require("object")?;
-                this.fetch_data(&object)?;
-                this.src_idx = 0;
+// compute the delay before a retry attempt, as exponential backoff with
+// jitter derived from the seed so concurrent scans don't retry in lockstep
+fn backoff_delay_ms(attempt: u32, seed: u64) -> u64 {
+    let backoff = RETRY_BASE_DELAY_MS.saturating_mul(1 << attempt.min(16));
 
-                if this.src_idx >= this.src_rows.len() {
-                    return Ok(None);
-                }
-            } else {
-                return Ok(None);
-            }
-        }
+    // splitmix64, which is cheap and good enough to decorrelate retries
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
 
-        let src_row = &this.src_rows[this.src_idx];
-        for tgt_col in ctx.get_columns() {
-            let tgt_col_name = tgt_col.name();
+    // keep half the backoff and randomize the other half
+    backoff / 2 + z % (backoff / 2 + 1)
+}
 
-            // Handle nested properties
-            let src_value = if tgt_col_name.contains(".") {
-                let parts: Vec<&str> = tgt_col_name.split('.').collect();
-                let mut current = src_row;
-                for part in parts {
-                    current = match current.get(part) {
-                        Some(v) => v,
-                        None => return Err(format!("source column '{}' not found", tgt_col_name)),
-                    };
-                }
-                current
-            } else {
-                match src_row.get(&tgt_col_name) {
-                    Some(v) => v,
-                    None => {
-                        // Try to get from properties object if direct access fails
-                        src_row
-                            .get("properties")
-                            .and_then(|props| props.get(&tgt_col_name))
-                            .ok_or(format!("source column '{}' not found", tgt_col_name))?
-                    }
-                }
-            };
+// check if an object type looks like a custom object id, such as `2-3508482`
+// or `p1234_cars`
+fn is_custom_object_type(object: &str) -> bool {
+    let is_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    match object.split_once('-') {
+        Some((prefix, id)) => is_digits(prefix) && is_digits(id),
+        None => object
+            .strip_prefix('p')
+            .and_then(|rest| rest.split_once('_'))
+            .is_some_and(|(portal, name)| is_digits(portal) && !name.is_empty()),
+    }
+}
 
-            let cell = match tgt_col.type_oid() {
-                TypeOid::Bool => {
-                    if src_value.is_null() {
-                        None
-                    } else {
-                        src_value.as_bool().map(Cell::Bool)
-                    }
-                }
-                TypeOid::String => {
-                    if src_value.is_null() {
-                        None
-                    } else {
-                        src_value.as_str().map(|v| Cell::String(v.to_owned()))
-                    }
-                }
-                TypeOid::Timestamp => {
-                    if src_value.is_null() {
-                        None
-                    } else {
-                        src_value.as_str().and_then(|v| {
-                            time::parse_from_rfc3339(v).ok().map(Cell::Timestamp)
-                        })
-                    }
-                }
-                TypeOid::Json => {
-                    if src_value.is_null() {
-                        None
-                    } else {
-                        Some(Cell::Json(src_value.to_string()))
-                    }
-                }
-                _ => {
-                    // Handle numeric values by converting them to strings
-                    if src_value.is_null() {
-                        None
-                    } else if src_value.is_number() {
-                        Some(Cell::String(src_value.to_string()))
-                    } else {
-                        None
-                    }
-                }
-            }.ok_or(format!(
-                "cannot convert column '{}' to type {:?}",
-                tgt_col_name,
-                tgt_col.type_oid()
-            ))?;
+// build the error for a failed response, recognising expired OAuth tokens
+// so they aren't mistaken for a wrong token
+fn status_error(resp: &http::Response, refresh_configured: bool) -> FdwError {
+    let body: Option<JsonValue> = serde_json::from_str(&resp.body).ok();
+    let field = |key: &str| {
+        body.as_ref()
+            .and_then(|b| b.get(key))
+            .and_then(|v| v.as_str())
+            .map(String::from)
+    };
 
-            row.push(Some(&cell));
-        }
+    if resp.status_code == 401
+        && !refresh_configured
+        && field("category").as_deref() == Some("EXPIRED_AUTHENTICATION")
+    {
+        return format!(
+            "HubSpot access token appears to have expired ({}), and refresh credentials \
+             ('refresh_token', 'client_id' and 'client_secret') are not configured",
+            field("message").unwrap_or_default()
+        );
+    }
 
-        this.src_idx += 1;
-        Ok(Some(1))
+    format!(
+        "HubSpot API request failed with status {}: {}",
+        resp.status_code,
+        field("message").unwrap_or_else(|| resp.body.clone())
+    )
+}
+
+// Substitute `{object}` and `{<option>}` placeholders in an endpoint
+// template with the object type and the table's option values
+fn render_endpoint_template(
+    template: &str,
+    object: &str,
+    opts: &impl OptionSource,
+) -> Result<String, FdwError> {
+    let mut endpoint = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        endpoint.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed placeholder in endpoint_template '{}'", template))?;
+        let name = &rest[start + 1..start + end];
+        let value = match name {
+            "object" => object.to_owned(),
+            _ => opts.get(name).ok_or_else(|| {
+                format!("endpoint_template placeholder '{{{}}}' has no matching option", name)
+            })?,
+        };
+        endpoint.push_str(&url_encode(&value));
+        rest = &rest[start + end + 1..];
     }
+    endpoint.push_str(rest);
 
-    fn re_scan(_ctx: &Context) -> FdwResult {
-        let this = Self::this_mut();
-        this.src_idx = 0;
-        Ok(())
+    if !endpoint.starts_with('/') {
+        return Err(format!("endpoint_template '{}' must start with '/'", template));
     }
+    Ok(endpoint)
+}
 
-    fn end_scan(_ctx: &Context) -> FdwResult {
-        let this = Self::this_mut();
-        this.src_rows.clear();
-        this.src_idx = 0;
-        this.after = None;
-        this.has_more = false;
-        Ok(())
+// FNV-1a hash of the parts, which tells servers apart without keeping their
+// credentials around in the clear
+fn fingerprint(parts: &[&str]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for part in parts {
+        for byte in part.bytes().chain(std::iter::once(0)) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
     }
+    hash
+}
 
-    fn begin_modify(_ctx: &Context) -> FdwResult {
-        Err("This FDW is read-only".to_string())
+// percent-encode a string for use in a URL path segment or query value
+fn url_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// find a column's source value, either by a dotted path or by name at the top
+// level or inside the record's `properties` object
+fn lookup_value<'a>(src_row: &'a JsonValue, name: &str) -> Option<&'a JsonValue> {
+    if name.contains('.') {
+        name.split('.')
+            .try_fold(src_row, |current, part| current.get(part))
+    } else {
+        src_row
+            .get(name)
+            .or_else(|| src_row.get("properties").and_then(|props| props.get(name)))
+            .or_else(|| {
+                // `<property>_history` columns map to the property's history
+                let property = name.strip_suffix("_history")?;
+                src_row.get("propertiesWithHistory")?.get(property)
+            })
     }
+}
 
-    fn insert(_ctx: &Context, _row: &Row) -> FdwResult {
-        Err("This FDW is read-only".to_string())
+// parse a response body as JSON, explaining what came back instead when a
+// proxy answers with something else, like an HTML error page
+fn parse_json_body(resp: &http::Response) -> Result<JsonValue, FdwError> {
+    // writes like GDPR deletes answer with no content at all
+    if resp.status_code == 204 {
+        return Ok(JsonValue::Null);
+    }
+    let content_type = resp
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+        .map(|(_, value)| value.as_str());
+    let snippet = || resp.body.chars().take(200).collect::<String>();
+    if let Some(content_type) = content_type.filter(|ct| !ct.contains("json")) {
+        return Err(format!(
+            "expected a JSON response but got content type '{}' with status {}: {}",
+            content_type,
+            resp.status_code,
+            snippet()
+        ));
     }
+    serde_json::from_str(&resp.body)
+        .map_err(|e| format!("response is not valid JSON ({}): {}", e, snippet()))
+}
 
-    fn update(_ctx: &Context, _rowid: Cell, _row: &Row) -> FdwResult {
-        Err("This FDW is read-only".to_string())
+// work out which properties need fetching for the selected columns
+fn projected_properties(columns: &[String], history_properties: &[String]) -> Vec<String> {
+    columns
+        .iter()
+        .filter_map(|name| match name.split_once('.') {
+            Some(("properties", property)) => Some(property.to_owned()),
+            Some(_) => None,
+            None if TOP_LEVEL_KEYS.contains(&name.as_str()) => None,
+            None if PSEUDO_COLUMNS.contains(&name.as_str()) => None,
+            None if ["_history", "_source"].iter().any(|suffix| {
+                name.strip_suffix(suffix)
+                    .is_some_and(|p| history_properties.iter().any(|h| h == p))
+            }) =>
+            {
+                None
+            }
+            None => Some(name.to_owned()),
+        })
+        .fold(Vec::new(), |mut properties, property| {
+            // several columns can read the same property
+            if !properties.contains(&property) {
+                properties.push(property);
+            }
+            properties
+        })
+}
+
+// extract a page's records from the object's results path, laid out either
+// as an array or as a map keyed by id
+fn extract_records(spec: &ObjectSpec, resp_json: &JsonValue) -> Result<Vec<JsonValue>, FdwError> {
+    if spec.revisions {
+        return Ok(property_revisions(resp_json));
+    }
+    let results = spec
+        .results_path
+        .split('.')
+        .try_fold(resp_json, |current, part| current.get(part));
+    match (spec.results_shape, results) {
+        (ResultsShape::Auto | ResultsShape::Array, Some(JsonValue::Array(array))) => {
+            Ok(array.clone())
+        }
+        (ResultsShape::Auto | ResultsShape::Map, Some(JsonValue::Object(map))) => {
+            // records keyed by id may leave the id out of the record
+            Ok(map
+                .iter()
+                .map(|(id, record)| {
+                    let mut record = record.clone();
+                    if let Some(obj) = record.as_object_mut() {
+                        obj.entry("id").or_insert_with(|| JsonValue::String(id.clone()));
+                    }
+                    record
+                })
+                .collect())
+        }
+        (ResultsShape::Auto, _) => Ok(Vec::new()),
+        (shape, _) => Err(format!(
+            "expected results at '{}' to be {}",
+            spec.results_path,
+            if shape == ResultsShape::Map { "an object" } else { "an array" }
+        )),
     }
+}
 
-    fn delete(_ctx: &Context, _rowid: Cell) -> FdwResult {
-        Err("This FDW is read-only".to_string())
+// group a record's property history into revisions, newest first, each
+// carrying the properties it changed under `changes`
+fn property_revisions(src_row: &JsonValue) -> Vec<JsonValue> {
+    let id = src_row.get("id").cloned().unwrap_or(JsonValue::Null);
+    let mut revisions: Vec<JsonValue> = Vec::new();
+    let history = src_row.get("propertiesWithHistory").and_then(|v| v.as_object());
+    for (property, versions) in history.into_iter().flatten() {
+        for version in versions.as_array().into_iter().flatten() {
+            let field = |key: &str| version.get(key).cloned().unwrap_or(JsonValue::Null);
+            let timestamp = field("timestamp");
+            let source_type = field("sourceType");
+            let source_id = field("sourceId");
+
+            // changes made together share their timestamp and source
+            let position = revisions.iter().position(|r| {
+                r["timestamp"] == timestamp
+                    && r["sourceType"] == source_type
+                    && r["sourceId"] == source_id
+            });
+            let index = match position {
+                Some(index) => index,
+                None => {
+                    revisions.push(serde_json::json!({
+                        "id": id,
+                        "timestamp": timestamp,
+                        "sourceType": source_type,
+                        "sourceId": source_id,
+                        "changes": {},
+                    }));
+                    revisions.len() - 1
+                }
+            };
+            revisions[index]["changes"][property] = field("value");
+        }
     }
 
-    fn end_modify(_ctx: &Context) -> FdwResult {
-        Err("This FDW is read-only".to_string())
+    // RFC 3339 timestamps in HubSpot's format sort as text
+    revisions.sort_by(|a, b| b["timestamp"].as_str().cmp(&a["timestamp"].as_str()));
+    revisions
+}
+
+// Normalize a phone number to E.164. Numbers without a country code are
+// only taken as North American when they have ten digits, and anything
+// with extensions or letters is left alone
+fn phone_e164(phone: &str) -> Option<String> {
+    let phone = phone.trim();
+    let (international, rest) = match phone.strip_prefix('+') {
+        Some(rest) => (true, rest),
+        None => (false, phone),
+    };
+    if !rest
+        .chars()
+        .all(|c| c.is_ascii_digit() || matches!(c, ' ' | '-' | '.' | '(' | ')'))
+    {
+        return None;
+    }
+    let digits: String = rest.chars().filter(char::is_ascii_digit).collect();
+    let starts_with = |prefix: char| digits.starts_with(prefix);
+    match digits.len() {
+        8..=15 if international => Some(format!("+{}", digits)),
+        10 if !starts_with('0') && !starts_with('1') => Some(format!("+1{}", digits)),
+        11 if starts_with('1') => Some(format!("+{}", digits)),
+        _ => None,
     }
 }
 
-bindings::export!(HubspotFdw with_types_in bindings);
+// format a qual's value the way search filters take it
+fn cell_to_filter_value(cell: &Cell) -> Option<String> {
+    match cell {
+        Cell::String(v) => Some(v.clone()),
+        Cell::I16(v) => Some(v.to_string()),
+        Cell::I32(v) => Some(v.to_string()),
+        Cell::I64(v) => Some(v.to_string()),
+        Cell::Bool(v) => Some(v.to_string()),
+        _ => None,
+    }
+}
+
+// whether the quals can't all hold, as when a column must equal two
+// different values
+fn contradictory_quals(quals: &[QualSpec]) -> bool {
+    let mut equalities: HashMap<&str, String> = HashMap::new();
+    for qual in quals {
+        if qual.operator != "=" || qual.has_param {
+            continue;
+        }
+        let Value::Cell(cell) = &qual.value else {
+            continue;
+        };
+        let Some(value) = cell_to_filter_value(cell) else {
+            continue;
+        };
+        match equalities.get(qual.field.as_str()) {
+            Some(other) if *other != value => return true,
+            Some(_) => {}
+            None => {
+                equalities.insert(&qual.field, value);
+            }
+        }
+    }
+    false
+}
+
+// read a record id from a row id or column cell
+fn cell_to_id(cell: &Cell) -> Result<String, FdwError> {
+    match cell {
+        Cell::String(id) => Ok(id.clone()),
+        Cell::I64(id) => Ok(id.to_string()),
+        Cell::I32(id) => Ok(id.to_string()),
+        _ => Err(format!("unsupported record id {:?}", cell)),
+    }
+}
+
+// read an integer from a JSON number or a quoted numeric string
+fn json_integer(value: &JsonValue) -> Option<i64> {
+    value
+        .as_i64()
+        .or_else(|| value.as_str().and_then(|v| v.trim().parse().ok()))
+}
+
+// check if a source record carries a non-null value for the property
+fn has_property(src_row: &JsonValue, name: &str) -> bool {
+    lookup_value(src_row, name).is_some_and(|v| !v.is_null())
+}
+
+// rebuild a JSON value with object keys in sorted order, so equal records
+// always serialize to byte-identical text
+fn sort_json_keys(value: &JsonValue) -> JsonValue {
+    match value {
+        JsonValue::Object(map) => {
+            let mut entries: Vec<(&String, &JsonValue)> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            JsonValue::Object(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| (k.clone(), sort_json_keys(v)))
+                    .collect(),
+            )
+        }
+        JsonValue::Array(array) => JsonValue::Array(array.iter().map(sort_json_keys).collect()),
+        _ => value.clone(),
+    }
+}
+
+impl HubspotFdw {
+    // initialise FDW instance
+    fn init_instance() {
+        let instance = Self::default();
+        unsafe {
+            INSTANCE = Box::leak(Box::new(instance));
+        }
+    }
+
+    fn this_mut() -> &'static mut Self {
+        unsafe { &mut (*INSTANCE) }
+    }
+
+    fn rate_limiter() -> &'static mut RateLimiter {
+        unsafe {
+            if RATE_LIMITER.is_null() {
+                RATE_LIMITER = Box::leak(Box::new(RateLimiter::default()));
+            }
+            &mut (*RATE_LIMITER)
+        }
+    }
+
+    fn object_schema_cache() -> &'static mut HashMap<u64, Vec<String>> {
+        unsafe {
+            if OBJECT_SCHEMAS.is_null() {
+                OBJECT_SCHEMAS = Box::leak(Box::new(HashMap::new()));
+            }
+            &mut (*OBJECT_SCHEMAS)
+        }
+    }
+
+    // identify the server by its API URL and credentials, which stay the
+    // same across token refreshes
+    fn server_fingerprint(&self) -> u64 {
+        match &self.oauth_refresh {
+            Some(oauth) => fingerprint(&[
+                &self.server_base_url,
+                &oauth.client_id,
+                &oauth.refresh_token,
+            ]),
+            None => fingerprint(&[&self.server_base_url, &self.api_key]),
+        }
+    }
+
+    // take a token from the shared bucket, waiting for the next refill when
+    // it's empty
+    fn throttle(&self) -> Result<(), FdwError> {
+        let Some(rate_limit) = self.rate_limit_per_sec else {
+            return Ok(());
+        };
+        let limiter = Self::rate_limiter();
+        while !limiter.try_take(time::epoch_secs(), rate_limit) {
+            self.check_deadline(1000)?;
+            time::sleep(1000);
+        }
+        Ok(())
+    }
+
+    // whether a response status is worth retrying, where server errors can
+    // opt out of retries to fail fast
+    fn is_retryable(&self, status_code: u16) -> bool {
+        let retry_status_codes = if self.in_modify {
+            &self.write_retry_status_codes
+        } else {
+            &self.retry_status_codes
+        };
+        retry_status_codes.contains(&status_code) && (status_code < 500 || self.retry_on_5xx)
+    }
+
+    // send a request, retrying rate-limited and server error responses
+    fn make_request(&mut self, req: &http::Request) -> Result<JsonValue, FdwError> {
+        // tag the request so it can be traced in HubSpot's logs, keeping the
+        // same id across retries. The wasm sandbox has no good randomness, so
+        // it's built from the time, object, a counter and the page cursor
+        self.fetch_seq += 1;
+        let request_id = format!(
+            "hubspot-fdw-{}-{}-{}-{}",
+            time::epoch_secs(),
+            self.object,
+            self.fetch_seq,
+            self.after.as_deref().unwrap_or("0")
+        );
+        let mut req = req.clone();
+        req.headers.push(("x-request-id".to_owned(), request_id.clone()));
+        self.last_error = None;
+
+        // machine-readable request plan for bug reports, without the token
+        if self.debug_plan {
+            let headers: serde_json::Map<String, JsonValue> = req
+                .headers
+                .iter()
+                .map(|(name, value)| {
+                    let value = if name.eq_ignore_ascii_case("authorization") {
+                        "Bearer [redacted]"
+                    } else {
+                        value
+                    };
+                    (name.clone(), JsonValue::String(value.to_owned()))
+                })
+                .collect();
+            let plan = serde_json::json!({
+                "object": self.object,
+                "endpoint": self.object_spec.endpoint,
+                "method": match req.method {
+                    http::Method::Get => "GET",
+                    http::Method::Post => "POST",
+                    http::Method::Put => "PUT",
+                    http::Method::Patch => "PATCH",
+                    http::Method::Delete => "DELETE",
+                },
+                "url": req.url,
+                "headers": headers,
+                "body": serde_json::from_str::<JsonValue>(&req.body)
+                    .unwrap_or_else(|_| JsonValue::String(req.body.clone())),
+            });
+            utils::report_info(&format!("request plan: {}", plan));
+        }
+
+        let mut attempt = 0;
+        let mut refreshed = false;
+        loop {
+            self.check_deadline(0)?;
+            self.throttle()?;
+
+            // refresh ahead of time when the token is known to have expired
+            let expired = self
+                .token_expires_at
+                .is_some_and(|expires_at| expires_at <= time::epoch_secs());
+            if self.oauth_refresh.is_some() && expired && !refreshed {
+                self.refresh_access_token()?;
+                refreshed = true;
+            }
+            self.set_authorization(&mut req);
+            let req = &req;
+
+            self.request_seq += 1;
+            let resp = match req.method {
+                http::Method::Get => http::get(req),
+                http::Method::Post => http::post(req),
+                http::Method::Put => http::put(req),
+                http::Method::Patch => http::patch(req),
+                http::Method::Delete => http::delete(req),
+            }?;
+            let correlation_id = resp
+                .headers
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case("x-hubspot-correlation-id"))
+                .map(|(_, value)| value.as_str())
+                .unwrap_or("unknown");
+
+            // refresh the access token once and try again
+            if resp.status_code == 401 && self.oauth_refresh.is_some() && !refreshed {
+                self.refresh_access_token()?;
+                refreshed = true;
+                continue;
+            }
+
+            if self.is_retryable(resp.status_code) && attempt < self.max_retries {
+                // mix the request sequence with the wall clock so separate
+                // scans pick different delays
+                let seed = self.request_seq ^ (time::epoch_secs() as u64).rotate_left(32);
+                let delay = backoff_delay_ms(attempt, seed);
+                self.check_deadline(delay)?;
+                utils::report_info(&format!(
+                    "HubSpot API returned status {}, retrying in {} ms \
+                     (request id {}, correlation id {})",
+                    resp.status_code, delay, request_id, correlation_id
+                ));
+                time::sleep(delay);
+                attempt += 1;
+                continue;
+            }
+
+            if resp.status_code >= 400 {
+                self.last_error = serde_json::from_str(&resp.body).ok();
+                return Err(format!(
+                    "{} (request id {}, correlation id {})",
+                    status_error(&resp, self.oauth_refresh.is_some()),
+                    request_id,
+                    correlation_id
+                ));
+            }
+
+            return parse_json_body(&resp);
+        }
+    }
+
+    // fail once the scan has run, or would run after waiting `wait_ms`,
+    // past its deadline. The host clock only has second resolution
+    fn check_deadline(&self, wait_ms: u64) -> Result<(), FdwError> {
+        let (Some(deadline_ms), Some(started_ms)) = (self.scan_deadline_ms, self.scan_started_ms)
+        else {
+            return Ok(());
+        };
+        let elapsed_ms = time::epoch_secs() * 1000 - started_ms + wait_ms as i64;
+        if elapsed_ms > deadline_ms {
+            return Err(format!(
+                "scan deadline of {} ms exceeded after fetching {} pages",
+                deadline_ms, self.pages_fetched
+            ));
+        }
+        Ok(())
+    }
+
+    // point a request's authorization header at the current access token
+    fn set_authorization(&self, req: &mut http::Request) {
+        for (name, value) in req.headers.iter_mut() {
+            if name == "authorization" {
+                *value = format!("Bearer {}", self.api_key);
+            }
+        }
+    }
+
+    // the API base for a table, its own `api_url` if the server allows one
+    fn table_base_url(&self, opts: &impl OptionSource) -> Result<String, FdwError> {
+        match parse_api_url(opts)? {
+            Some(_) if !self.allow_table_api_url => Err(
+                "table option 'api_url' requires the server option allow_table_api_url 'true'"
+                    .to_string(),
+            ),
+            Some(url) => Ok(url),
+            None => Ok(self.server_base_url.clone()),
+        }
+    }
+
+    // exchange the refresh token for a new access token, optionally saving it
+    // in the FDW's stats metadata so other workers can reuse it
+    fn refresh_access_token(&mut self) -> Result<(), FdwError> {
+        let oauth = self
+            .oauth_refresh
+            .as_ref()
+            .ok_or("OAuth refresh credentials are not configured")?;
+        let req = http::Request {
+            method: http::Method::Post,
+            url: format!("{}/oauth/v1/token", self.server_base_url),
+            headers: vec![(
+                "content-type".to_owned(),
+                "application/x-www-form-urlencoded".to_owned(),
+            )],
+            body: format!(
+                "grant_type=refresh_token&client_id={}&client_secret={}&refresh_token={}",
+                url_encode(&oauth.client_id),
+                url_encode(&oauth.client_secret),
+                url_encode(&oauth.refresh_token)
+            ),
+        };
+        let resp = http::post(&req)?;
+        let client_id = oauth.client_id.clone();
+        if resp.status_code >= 400 {
+            return Err(format!(
+                "failed to refresh HubSpot access token: {}",
+                status_error(&resp, true)
+            ));
+        }
+
+        let resp_json = parse_json_body(&resp)?;
+        self.api_key = resp_json
+            .get("access_token")
+            .and_then(|v| v.as_str())
+            .ok_or("token refresh response has no access_token")?
+            .to_owned();
+        self.token_expires_at = resp_json
+            .get("expires_in")
+            .and_then(|v| v.as_i64())
+            .map(|expires_in| time::epoch_secs() + expires_in);
+        utils::report_info("Refreshed HubSpot access token");
+
+        // The vault is read-only to the FDW, so the token is shared through
+        // the stats metadata instead, under a key of the server's own. The
+        // in-memory token above is already in use, so this is best effort
+        if self.persist_refreshed_token {
+            let metadata = serde_json::json!({
+                "api_url": self.server_base_url,
+                "client_id": client_id,
+                "access_token": self.api_key,
+                "expires_at": self.token_expires_at,
+            });
+            stats::set_metadata(&self.token_metadata_key(), &Some(metadata.to_string()));
+        }
+
+        Ok(())
+    }
+
+    // the stats metadata key a server's refreshed token is persisted under,
+    // which includes its API URL, client id and refresh token
+    fn token_metadata_key(&self) -> String {
+        format!("{}-{:016x}", FDW_NAME, self.server_fingerprint())
+    }
+
+    // reuse an access token persisted by another worker of the same server,
+    // if it's still valid
+    fn load_persisted_token(&mut self) {
+        let Some(metadata) = stats::get_metadata(&self.token_metadata_key()) else {
+            return;
+        };
+        let Ok(metadata) = serde_json::from_str::<JsonValue>(&metadata) else {
+            return;
+        };
+        let field = |key: &str| metadata.get(key).and_then(|v| v.as_str());
+        let same_server = self.oauth_refresh.as_ref().is_some_and(|oauth| {
+            field("api_url") == Some(self.server_base_url.as_str())
+                && field("client_id") == Some(oauth.client_id.as_str())
+        });
+        if !same_server {
+            return;
+        }
+        let access_token = field("access_token");
+        let expires_at = metadata.get("expires_at").and_then(|v| v.as_i64());
+        if let (Some(access_token), Some(expires_at)) = (access_token, expires_at) {
+            if expires_at > time::epoch_secs() {
+                self.api_key = access_token.to_owned();
+                self.token_expires_at = Some(expires_at);
+            }
+        }
+    }
+
+    fn headers(&self) -> http::Headers {
+        vec![
+            ("authorization".to_owned(), format!("Bearer {}", self.api_key)),
+            ("content-type".to_owned(), "application/json".to_owned()),
+        ]
+    }
+
+    // fetch the object types defined in the portal, including custom objects
+    fn fetch_object_schemas(&mut self) -> Result<(), FdwError> {
+        let server = self.server_fingerprint();
+        if let Some(object_schemas) = Self::object_schema_cache().get(&server) {
+            self.object_schemas = Some(object_schemas.clone());
+            return Ok(());
+        }
+
+        let req = http::Request {
+            method: http::Method::Get,
+            url: format!("{}/crm/v3/schemas", self.base_url),
+            headers: self.headers(),
+            body: String::default(),
+        };
+        let resp_json = self.make_request(&req)?;
+
+        // custom objects can be referred to by name, id or qualified name
+        let mut object_schemas = Vec::new();
+        if let Some(results) = resp_json.get("results").and_then(|v| v.as_array()) {
+            for schema in results {
+                for key in ["name", "objectTypeId", "fullyQualifiedName"] {
+                    if let Some(value) = schema.get(key).and_then(|v| v.as_str()) {
+                        object_schemas.push(value.to_owned());
+                    }
+                }
+            }
+        }
+        Self::object_schema_cache().insert(server, object_schemas.clone());
+        self.object_schemas = Some(object_schemas);
+
+        Ok(())
+    }
+
+    // fetch the labels of the object's enumeration property options, keyed
+    // by property and then by internal value
+    fn fetch_enum_labels(&mut self) -> Result<(), FdwError> {
+        let req = http::Request {
+            method: http::Method::Get,
+            url: format!("{}/crm/v3/properties/{}", self.base_url, self.object),
+            headers: self.headers(),
+            body: String::default(),
+        };
+        let resp_json = self.make_request(&req)?;
+
+        let mut enum_labels = HashMap::new();
+        if let Some(results) = resp_json.get("results").and_then(|v| v.as_array()) {
+            for property in results {
+                let Some(name) = property.get("name").and_then(|v| v.as_str()) else {
+                    continue;
+                };
+                let labels: HashMap<String, String> = property
+                    .get("options")
+                    .and_then(|v| v.as_array())
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|option| {
+                        let value = option.get("value")?.as_str()?;
+                        let label = option.get("label")?.as_str()?;
+                        Some((value.to_owned(), label.to_owned()))
+                    })
+                    .collect();
+                if !labels.is_empty() {
+                    enum_labels.insert(name.to_owned(), labels);
+                }
+            }
+        }
+        self.enum_labels = enum_labels;
+
+        Ok(())
+    }
+
+    // build the request for the next page from the object's list endpoint
+    fn list_request(&self) -> http::Request {
+        let spec = &self.object_spec;
+        let mut url = format!("{}{}", self.base_url, spec.endpoint);
+
+        // Add query parameters
+        let mut params = Vec::new();
+        if let Some(page_size) = self.page_size {
+            params.push(format!("limit={}", page_size));
+        }
+        if !spec.properties.is_empty() {
+            params.push(format!("properties={}", spec.properties.join(",")));
+        }
+        if !self.history_properties.is_empty() {
+            params.push(format!(
+                "propertiesWithHistory={}",
+                self.history_properties.join(",")
+            ));
+        }
+        if !self.association_targets.is_empty() {
+            params.push(format!(
+                "associations={}",
+                self.association_targets.join(",")
+            ));
+        }
+        if let Some(extra_params) = &spec.extra_params {
+            params.push(extra_params.clone());
+        }
+
+        // POST reads carry the paging cursor in the body instead
+        let mut body = spec.body.clone();
+        if let Some(after) = &self.after {
+            match body.as_mut().and_then(|b| b.as_object_mut()) {
+                Some(obj) => {
+                    obj.insert("after".to_owned(), JsonValue::String(after.clone()));
+                }
+                None => params.push(format!("after={}", after)),
+            }
+        }
+        if !params.is_empty() {
+            url.push('?');
+            url.push_str(&params.join("&"));
+        }
+
+        http::Request {
+            method: spec.method,
+            url,
+            headers: self.headers(),
+            body: body.map(|b| b.to_string()).unwrap_or_default(),
+        }
+    }
+
+    // filters applied to every search request
+    fn search_filters(&self) -> Vec<JsonValue> {
+        let mut filters = self.qual_filters.clone();
+        if let Some(owner_id) = &self.owner_id {
+            filters.push(serde_json::json!({
+                "propertyName": "hubspot_owner_id",
+                "operator": "EQ",
+                "value": owner_id,
+            }));
+        }
+        if let Some(modified_since) = self.modified_since {
+            filters.push(serde_json::json!({
+                "propertyName": self.object_spec.modified_property,
+                "operator": "GTE",
+                "value": modified_since.to_string(),
+            }));
+        }
+        if let Some((start, end)) = self.search_window {
+            filters.push(serde_json::json!({
+                "propertyName": self.partition_property,
+                "operator": "GTE",
+                "value": start.to_string(),
+            }));
+            filters.push(serde_json::json!({
+                "propertyName": self.partition_property,
+                "operator": "LT",
+                "value": end.to_string(),
+            }));
+        }
+        filters
+    }
+
+    // go back to the first page of the scan
+    fn reset_paging(&mut self) {
+        self.after = None;
+        self.has_more = false;
+        self.pages_fetched = 0;
+        self.empty_pages = 0;
+        self.first_page_properties = None;
+        self.pending_windows.clear();
+        self.seen_ids.clear();
+        self.search_window = None;
+    }
+
+    // build the request for the next page from the CRM search endpoint,
+    // which takes the property list in the body rather than the URL
+    fn search_request(&self) -> http::Request {
+        let spec = &self.object_spec;
+        let mut body = serde_json::json!({
+            "properties": spec.properties,
+            "limit": self.page_size.unwrap_or(SEARCH_MAX_PAGE_SIZE),
+        });
+        let filters = self.search_filters();
+        if !filters.is_empty() {
+            body["filterGroups"] = serde_json::json!([{ "filters": filters }]);
+        }
+        if self.stable_order {
+            body["sorts"] = serde_json::json!([{
+                "propertyName": "hs_object_id",
+                "direction": "ASCENDING",
+            }]);
+        }
+        if let Some(after) = &self.after {
+            body["after"] = JsonValue::String(after.clone());
+        }
+
+        http::Request {
+            method: http::Method::Post,
+            url: format!("{}{}/search", self.base_url, spec.endpoint),
+            headers: self.headers(),
+            body: body.to_string(),
+        }
+    }
+
+    // Translate quals on plain property columns into search filters, using
+    // at most `max_filters`. Postgres still checks every qual, so quals which
+    // don't translate, or columns whose values are transformed, are left to it
+    fn translate_quals(&self, quals: &[QualSpec], max_filters: usize) -> Vec<JsonValue> {
+        let mut filters = Vec::new();
+        for qual in quals {
+            if filters.len() >= max_filters || qual.has_param {
+                continue;
+            }
+            let field = qual.field.clone();
+            if self.value_kinds.contains_key(&field)
+                || self.bool_text_formats.contains_key(&field)
+                || self.normalizations.contains_key(&field)
+                || self.multi_value_columns.contains(&field)
+                || self.provenance_column.as_ref() == Some(&field)
+            {
+                continue;
+            }
+            let name = self.source_name(&field);
+            let property = match name {
+                "id" => Some("hs_object_id".to_owned()),
+                _ => projected_properties(&[name.to_owned()], &self.history_properties).pop(),
+            };
+            let Some(property) = property.filter(|p| !self.enum_labels.contains_key(p)) else {
+                continue;
+            };
+
+            let values = |cells: &[Cell]| {
+                cells
+                    .iter()
+                    .map(cell_to_filter_value)
+                    .collect::<Option<Vec<_>>>()
+                    .filter(|values| values.len() <= SEARCH_MAX_FILTER_VALUES)
+            };
+            let filter = match (qual.operator.as_str(), &qual.value, qual.use_or) {
+                ("=", Value::Cell(cell), _) => cell_to_filter_value(cell)
+                    .map(|v| serde_json::json!({ "operator": "EQ", "value": v })),
+                ("<>", Value::Cell(cell), _) => cell_to_filter_value(cell)
+                    .map(|v| serde_json::json!({ "operator": "NEQ", "value": v })),
+                // IN lists take a `values` array rather than a single value
+                ("=", Value::Array(cells), true) => {
+                    values(cells).map(|v| serde_json::json!({ "operator": "IN", "values": v }))
+                }
+                ("<>", Value::Array(cells), false) => values(cells)
+                    .map(|v| serde_json::json!({ "operator": "NOT_IN", "values": v })),
+                _ => None,
+            };
+            if let Some(mut filter) = filter {
+                filter["propertyName"] = JsonValue::String(property);
+                filters.push(filter);
+            }
+        }
+        filters
+    }
+
+    // the property, or path, a column reads from the source record
+    fn source_name<'a>(&'a self, tgt_col_name: &'a str) -> &'a str {
+        self.property_map
+            .get(tgt_col_name)
+            .map(String::as_str)
+            .unwrap_or(tgt_col_name)
+    }
+
+    // convert a non-null source value to a cell of the column's type,
+    // returning None if the value can't be represented
+    fn convert_value(
+        &self,
+        src_value: &JsonValue,
+        tgt_col_name: &str,
+        type_oid: TypeOid,
+    ) -> Option<Cell> {
+        let value_kind = self.value_kinds.get(tgt_col_name).copied();
+        match type_oid {
+            TypeOid::I64 => {
+                if value_kind == Some(ValueKind::MillisToSecs) {
+                    json_integer(src_value).map(|ms| Cell::I64(ms.div_euclid(1000)))
+                } else if value_kind == Some(ValueKind::EpochMillisOut) {
+                    // accept ISO timestamps as well as values which are
+                    // already epoch millis
+                    src_value.as_str().and_then(|v| {
+                        time::parse_from_rfc3339(v)
+                            .ok()
+                            .map(|us| us.div_euclid(1000))
+                            .or_else(|| v.parse().ok())
+                            .map(Cell::I64)
+                    })
+                } else {
+                    // HubSpot returns property values as strings
+                    src_value
+                        .as_i64()
+                        .or_else(|| src_value.as_str().and_then(|v| v.parse().ok()))
+                        .map(Cell::I64)
+                }
+            }
+            // small enum codes, which may come quoted, range checked
+            TypeOid::I16 => json_integer(src_value)
+                .and_then(|v| i16::try_from(v).ok())
+                .map(Cell::I16),
+            TypeOid::Bool => src_value.as_bool().map(Cell::Bool),
+            // booleans, which HubSpot usually sends as text, in the column's
+            // chosen format
+            TypeOid::String if self.bool_text_formats.contains_key(tgt_col_name) => {
+                let value = match src_value {
+                    JsonValue::Bool(v) => Some(*v),
+                    JsonValue::String(v) if v == "true" => Some(true),
+                    JsonValue::String(v) if v == "false" => Some(false),
+                    _ => None,
+                };
+                match value {
+                    Some(v) => Some(Cell::String(
+                        self.bool_text_formats[tgt_col_name].format(v).to_owned(),
+                    )),
+                    None => src_value.as_str().map(|v| Cell::String(v.to_owned())),
+                }
+            }
+            // values which can't be confidently normalized are kept as they are
+            TypeOid::String if self.normalizations.contains_key(tgt_col_name) => {
+                src_value.as_str().map(|v| {
+                    let normalized = match self.normalizations[tgt_col_name] {
+                        Normalization::PhoneE164 => phone_e164(v),
+                    };
+                    Cell::String(normalized.unwrap_or_else(|| v.to_owned()))
+                })
+            }
+            // numbers keep their text as returned, trailing zeros included
+            TypeOid::String if self.preserve_number_text && src_value.is_number() => {
+                Some(Cell::String(src_value.to_string()))
+            }
+            // scalars nested in objects, like a numeric postal code in an
+            // address, read as text
+            TypeOid::String
+                if self.source_name(tgt_col_name).contains('.')
+                    && (src_value.is_number() || src_value.is_boolean()) =>
+            {
+                Some(Cell::String(src_value.to_string()))
+            }
+            TypeOid::String => src_value.as_str().map(|v| {
+                // multiple checkbox values are separated by semicolons, and
+                // values without a known label are kept as they are
+                let property = self.source_name(tgt_col_name);
+                let property = property.strip_prefix("properties.").unwrap_or(property);
+                match self.enum_labels.get(property) {
+                    Some(labels) => Cell::String(
+                        v.split(';')
+                            .map(|part| labels.get(part).map(String::as_str).unwrap_or(part))
+                            .collect::<Vec<_>>()
+                            .join(";"),
+                    ),
+                    None => Cell::String(v.to_owned()),
+                }
+            }),
+            // date properties come as `YYYY-MM-DD` or as a full datetime,
+            // which is truncated to its day in UTC
+            TypeOid::Date => src_value.as_str().and_then(|v| {
+                let datetime = match v.len() {
+                    10 => format!("{}T00:00:00Z", v),
+                    _ => v.to_owned(),
+                };
+                time::parse_from_rfc3339(&datetime).ok().map(|us| {
+                    let secs = us.div_euclid(1_000_000);
+                    Cell::Date(secs - secs.rem_euclid(86_400))
+                })
+            }),
+            TypeOid::Timestamp => src_value
+                .as_str()
+                .and_then(|v| time::parse_from_rfc3339(v).ok().map(Cell::Timestamp)),
+            TypeOid::Json => {
+                if self.json_sort_keys {
+                    Some(Cell::Json(sort_json_keys(src_value).to_string()))
+                } else {
+                    Some(Cell::Json(src_value.to_string()))
+                }
+            }
+            _ => {
+                // Handle numeric values by converting them to strings
+                if src_value.is_number() {
+                    Some(Cell::String(src_value.to_string()))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    // convert a source record into cells for the selected columns, also
+    // returning the (column, error) pairs of lenient conversion failures
+    fn convert_row(
+        &self,
+        src_row: &JsonValue,
+        columns: &[ColumnSpec],
+    ) -> Result<ConvertedRow, FdwError> {
+        // raw mode bypasses conversion, every column gets the JSON as is
+        if self.raw_mode != RawMode::Off {
+            let cells = columns.iter().map(|_| Some(Cell::Json(src_row.to_string()))).collect();
+            return Ok((cells, Vec::new()));
+        }
+
+        let mut cells = Vec::with_capacity(columns.len());
+        let mut failures = Vec::new();
+        let mut errors_col = None;
+
+        for tgt_col in columns {
+            let tgt_col_name = tgt_col.name.clone();
+            let type_oid = tgt_col.type_oid;
+
+            // filled in once all other columns are converted
+            if tgt_col_name == "_errors" {
+                errors_col = Some((cells.len(), type_oid));
+                cells.push(None);
+                continue;
+            }
+
+            // when the record's page was fetched
+            if tgt_col_name == "_fetched_at" {
+                cells.push(Some(match type_oid {
+                    TypeOid::Timestamptz => Cell::Timestamptz(self.fetched_at),
+                    _ => Cell::Timestamp(self.fetched_at),
+                }));
+                continue;
+            }
+
+            // `associations.<target>.count` columns give how many records of
+            // the target are associated, zero when there are none
+            if let Some(target) = tgt_col_name
+                .strip_prefix("associations.")
+                .and_then(|rest| rest.strip_suffix(".count"))
+            {
+                let count = src_row
+                    .get("associations")
+                    .and_then(|v| v.get(target))
+                    .and_then(|v| v.get("results"))
+                    .and_then(|v| v.as_array())
+                    .map_or(0, |results| results.len());
+                cells.push(Some(Cell::I64(count as i64)));
+                continue;
+            }
+
+            // the label of an unnested association, NULL when unlabeled
+            if tgt_col_name == "association_label" {
+                let label = src_row.get("association_label").and_then(|v| v.as_str());
+                cells.push(label.map(|v| Cell::String(v.to_owned())));
+                continue;
+            }
+
+            // only ever written, to merge records
+            if tgt_col_name == "_merge_into" {
+                cells.push(None);
+                continue;
+            }
+
+            // which of the table's objects the record belongs to
+            if tgt_col_name == "_object_type" {
+                cells.push(Some(Cell::String(self.object.clone())));
+                continue;
+            }
+
+            // how many records the search matched, list endpoints don't say
+            if tgt_col_name == "_total" {
+                cells.push(self.search_total.map(Cell::I64));
+                continue;
+            }
+
+            // whether more pages follow the record's page
+            if tgt_col_name == "_has_more" {
+                cells.push(Some(Cell::Bool(self.has_more)));
+                continue;
+            }
+
+            // Whether the record was created or only modified since the CDC
+            // watermark, NULL when it's unchanged. Deleted records aren't
+            // returned, so deletes can't be captured this way
+            if tgt_col_name == "_op" {
+                let micros = |key: &str| {
+                    src_row
+                        .get(key)
+                        .and_then(|v| v.as_str())
+                        .and_then(|v| time::parse_from_rfc3339(v).ok())
+                };
+                let op = self.cdc_watermark.and_then(|watermark| {
+                    if micros("createdAt").is_some_and(|created| created > watermark) {
+                        Some("insert")
+                    } else if micros("updatedAt").is_some_and(|updated| updated > watermark) {
+                        Some("update")
+                    } else {
+                        None
+                    }
+                });
+                cells.push(op.map(|op| Cell::String(op.to_owned())));
+                continue;
+            }
+
+            // id_only scans deliberately carry nothing but the record id
+            if self.id_only && tgt_col_name != "id" {
+                cells.push(None);
+                continue;
+            }
+
+            // `<property>_source` columns of properties fetched with history
+            // give where the latest value came from, NULL without history
+            if let Some(property) = tgt_col_name
+                .strip_suffix("_source")
+                .filter(|p| self.history_properties.iter().any(|h| h == p))
+            {
+                let source_type = src_row
+                    .get("propertiesWithHistory")
+                    .and_then(|v| v.get(property))
+                    .and_then(|v| v.get(0))
+                    .and_then(|v| v.get("sourceType"))
+                    .and_then(|v| v.as_str());
+                cells.push(source_type.map(|v| Cell::String(v.to_owned())));
+                continue;
+            }
+
+            // the source attribution properties the record has, as one object
+            if self.provenance_column.as_ref() == Some(&tgt_col_name) {
+                let provenance: serde_json::Map<String, JsonValue> = src_row
+                    .get("properties")
+                    .and_then(|v| v.as_object())
+                    .into_iter()
+                    .flatten()
+                    .filter(|(name, _)| SOURCE_PROPERTIES.contains(&name.as_str()))
+                    .map(|(name, value)| (name.clone(), value.clone()))
+                    .collect();
+                cells.push(Some(Cell::Json(JsonValue::Object(provenance).to_string())));
+                continue;
+            }
+
+            // Json columns treat an absent property the same as a null one,
+            // as do CRM records without any `properties`, like those of
+            // association-only objects
+            let source_name = self.source_name(&tgt_col_name);
+            let src_value = lookup_value(src_row, source_name).or_else(|| {
+                self.lowercase_property_keys
+                    .then(|| lookup_value(src_row, &source_name.to_lowercase()))
+                    .flatten()
+            });
+            let src_value = match src_value {
+                Some(v) => Some(v),
+                None if matches!(type_oid, TypeOid::Json) => None,
+                None if self.object_spec.crm && src_row.get("properties").is_none() => None,
+                // tables over several objects have columns for properties
+                // only some of the objects have, like `hs_call_title`
+                None if self.objects.len() > 1
+                    && !source_name.contains('.')
+                    && src_row.get("properties").is_some() =>
+                {
+                    None
+                }
+                // lenient scans read missing nested values, like an address
+                // without a city, as NULL
+                None if self.source_name(&tgt_col_name).contains('.')
+                    && self.on_conversion_error != ConversionErrorMode::Error =>
+                {
+                    None
+                }
+                None => {
+                    return Err(format!(
+                        "{}: source column '{}' not found",
+                        self.object_label, tgt_col_name
+                    ))
+                }
+            };
+
+            // multi-value properties, such as a contact's secondary emails in
+            // `hs_additional_emails`, come as `;`-separated text and are
+            // emitted as a JSON array, which is empty when there are none
+            if self.multi_value_columns.contains(&tgt_col_name) {
+                let values: Vec<JsonValue> = match src_value {
+                    Some(JsonValue::String(v)) => v
+                        .split(';')
+                        .map(str::trim)
+                        .filter(|v| !v.is_empty())
+                        .map(|v| JsonValue::String(v.to_owned()))
+                        .collect(),
+                    Some(JsonValue::Array(values)) => values.clone(),
+                    _ => Vec::new(),
+                };
+                cells.push(Some(Cell::Json(JsonValue::Array(values).to_string())));
+                continue;
+            }
+
+            let src_value = match src_value.filter(|v| !v.is_null()) {
+                Some(v) => v,
+                None => {
+                    let cell = match type_oid {
+                        TypeOid::Json if self.json_null_literal => {
+                            Some(Cell::Json("null".to_owned()))
+                        }
+                        _ => None,
+                    };
+                    cells.push(cell);
+                    continue;
+                }
+            };
+
+            match self.convert_value(src_value, &tgt_col_name, type_oid) {
+                Some(cell) => cells.push(Some(cell)),
+                None => {
+                    // integers which overflow the column say so
+                    let out_of_range = matches!(type_oid, TypeOid::I16)
+                        && json_integer(src_value).is_some();
+                    let error = if out_of_range {
+                        format!("value {} is out of range for type {:?}", src_value, type_oid)
+                    } else {
+                        format!("cannot convert value {} to type {:?}", src_value, type_oid)
+                    };
+                    if self.on_conversion_error == ConversionErrorMode::Error {
+                        if out_of_range {
+                            return Err(format!(
+                                "{}: cannot convert column '{}': {}",
+                                self.object_label, tgt_col_name, error
+                            ));
+                        }
+                        return Err(format!(
+                            "{}: cannot convert column '{}' to type {:?}",
+                            self.object_label, tgt_col_name, type_oid
+                        ));
+                    }
+                    failures.push((tgt_col_name, error));
+                    cells.push(None);
+                }
+            }
+        }
+
+        // report what failed to convert, so lenient scans stay queryable
+        if let Some((idx, type_oid)) = errors_col {
+            if !failures.is_empty() {
+                cells[idx] = match type_oid {
+                    TypeOid::Json => {
+                        let errors: serde_json::Map<String, JsonValue> = failures
+                            .iter()
+                            .map(|(col, error)| (col.clone(), JsonValue::String(error.clone())))
+                            .collect();
+                        Some(Cell::Json(JsonValue::Object(errors).to_string()))
+                    }
+                    _ => {
+                        let errors: Vec<String> = failures
+                            .iter()
+                            .map(|(col, error)| format!("{}: {}", col, error))
+                            .collect();
+                        Some(Cell::String(errors.join("; ")))
+                    }
+                };
+            }
+        }
+
+        Ok((cells, failures))
+    }
+
+    // whether the last request failed on the search endpoint's cap on how
+    // far results can be paged through
+    fn is_pagination_limit_error(&self) -> bool {
+        self.last_error.as_ref().is_some_and(|error| {
+            ["category", "subCategory"]
+                .iter()
+                .any(|key| error.get(key).and_then(|v| v.as_str()) == Some("PAGINATION_LIMIT"))
+        })
+    }
+
+    // whether the scan can be served by the list endpoint, which can't filter
+    // and takes its properties in the URL
+    fn can_list(&self) -> bool {
+        self.owner_id.is_none()
+            && self.modified_since.is_none()
+            && self.qual_filters.is_empty()
+            && !self.stable_order
+            && self.list_request().url.len() <= MAX_URL_LENGTH
+    }
+
+    fn fetch_data(&mut self) -> Result<(), String> {
+        let req = if self.use_search {
+            self.search_request()
+        } else {
+            self.list_request()
+        };
+        let resp_json = match self.make_request(&req) {
+            Ok(resp_json) => resp_json,
+            Err(_) if self.use_search && self.is_pagination_limit_error() && self.can_list() => {
+                // The list endpoint has no result cap, so continue the scan
+                // there, skipping records search has already returned
+                utils::report_info(
+                    "Search hit HubSpot's pagination limit, continuing with the list endpoint",
+                );
+                let seen_ids = std::mem::take(&mut self.seen_ids);
+                self.use_search = false;
+                self.search_abandoned = true;
+                self.reset_paging();
+                self.seen_ids = seen_ids;
+                if let Some(cap) = self.object_spec.page_size_cap(false, false) {
+                    self.page_size = self.page_size.map(|size| size.min(cap));
+                }
+                return self.fetch_data();
+            }
+            Err(e) => return Err(e),
+        };
+        self.page_cursor = self.after.clone();
+        self.fetched_at = time::epoch_secs() * 1_000_000;
+        self.pages_fetched += 1;
+
+        // the first page covers the whole scan, later windows only part of it
+        if self.pages_fetched == 1 {
+            self.search_total = resp_json
+                .get("total")
+                .and_then(|v| v.as_i64())
+                .filter(|_| self.use_search);
+        }
+
+        // A search with more results than it can page through is split into
+        // windows on the partition property, in epoch millis, whose halves
+        // are scanned in turn and split again while they're still too big.
+        // Smaller searches aren't windowed at all
+        let total = resp_json.get("total").and_then(|v| v.as_u64()).unwrap_or(0);
+        if self.use_search && self.after.is_none() && total > SEARCH_RESULT_LIMIT {
+            let (start, end) = self.search_window.unwrap_or((0, SEARCH_WINDOW_END_MS));
+            if end - start > 1 {
+                let mid = start + (end - start) / 2;
+                utils::report_info(&format!(
+                    "Search window [{}, {}) on '{}' has {} results, splitting at {}",
+                    start, end, self.partition_property, total, mid
+                ));
+                self.pending_windows.push((mid, end));
+                self.search_window = Some((start, mid));
+                self.src_rows.clear();
+                self.has_more = true;
+                return Ok(());
+            }
+        }
+
+        // Extract pagination info, the last page comes without any
+        self.has_more = false;
+        if let Some(paging) = resp_json.get("paging") {
+            self.has_more = paging.get("next").is_some();
+            if self.has_more {
+                self.after = paging.get("next").and_then(|v| v.get("after")).and_then(|v| v.as_str()).map(String::from);
+            }
+        }
+
+        let next_page = self.has_more;
+
+        // Move on to the next search window once this one is exhausted
+        if !self.has_more {
+            if let Some(window) = self.pending_windows.pop() {
+                self.search_window = Some(window);
+                self.after = None;
+                self.has_more = true;
+            }
+        }
+
+        // Each page replaces the rows buffered from the previous one
+        self.src_rows.clear();
+        if self.raw_mode == RawMode::Page {
+            self.src_rows.push(resp_json);
+            return Ok(());
+        }
+
+        // Extract results
+        let records = extract_records(&self.object_spec, &resp_json)?;
+
+        // HubSpot occasionally sends empty pages which still point to a next
+        // one, so give up after a few in a row rather than loop forever
+        if !records.is_empty() {
+            self.empty_pages = 0;
+        } else if next_page {
+            self.empty_pages += 1;
+            if self.empty_pages >= self.max_empty_pages {
+                utils::report_warning(&format!(
+                    "{} consecutive empty pages which claim more results for {}, ending the \
+                     scan, results may be incomplete",
+                    self.empty_pages, self.object_label
+                ));
+                self.has_more = false;
+                self.pending_windows.clear();
+            }
+        }
+
+        // The list endpoint can leave out requested properties, so retry the
+        // scan through search once if one is null on the whole first page
+        if self.auto_search_fallback
+            && !self.use_search
+            && !self.search_abandoned
+            && self.pages_fetched == 1
+            && !records.is_empty()
+            && self.history_properties.is_empty()
+            && self.association_targets.is_empty()
+        {
+            let missing = self
+                .selected_properties
+                .iter()
+                .find(|p| !records.iter().any(|r| has_property(r, p)));
+            if let Some(missing) = missing {
+                utils::report_info(&format!(
+                    "Property '{}' is null on the first page from the list endpoint, \
+                     retrying with the search endpoint",
+                    missing
+                ));
+                self.use_search = true;
+                self.reset_paging();
+                return self.fetch_data();
+            }
+        }
+
+        // Subscription statuses don't repeat the contact's email, so copy the
+        // recipient onto each row
+        let recipient = resp_json.get("recipient");
+        for mut src_row in records {
+            if let (Some(recipient), Some(obj)) = (recipient, src_row.as_object_mut()) {
+                obj.entry("recipient").or_insert_with(|| recipient.clone());
+            }
+
+            // HubSpot's mixed-case keys, like the default contact properties,
+            // are read back lowercased
+            if self.lowercase_property_keys {
+                for key in ["properties", "propertiesWithHistory"] {
+                    if let Some(obj) = src_row.get_mut(key).and_then(|v| v.as_object_mut()) {
+                        *obj = std::mem::take(obj)
+                            .into_iter()
+                            .map(|(name, value)| (name.to_lowercase(), value))
+                            .collect();
+                    }
+                }
+            }
+
+            // keep only the most recent history entries, which come first
+            if let Some(limit) = self.history_limit {
+                if let Some(history) = src_row
+                    .get_mut("propertiesWithHistory")
+                    .and_then(|v| v.as_object_mut())
+                {
+                    for versions in history.values_mut() {
+                        if let Some(versions) = versions.as_array_mut() {
+                            versions.truncate(limit);
+                        }
+                    }
+                }
+            }
+
+            // partitioned searches can see a record twice if it changes
+            // between windows, as can a list scan taking over from search
+            if self.use_search || self.search_abandoned {
+                let id = src_row.get("id").and_then(|v| v.as_str()).unwrap_or_default();
+                if !self.seen_ids.insert(id.to_owned()) {
+                    continue;
+                }
+            }
+
+            self.src_rows.push(src_row);
+        }
+
+        if let Some(mode) = self.schema_drift {
+            self.check_schema_drift(mode)?;
+        }
+        if let Some(target) = self.unnest_target.clone() {
+            self.unnest_associations(&target)?;
+        }
+
+        Ok(())
+    }
+
+    // compare the selected properties present on this page with those of
+    // the first page, as drift otherwise shows up as sporadic NULLs
+    fn check_schema_drift(&mut self, mode: SchemaDriftMode) -> Result<(), FdwError> {
+        if self.src_rows.is_empty() {
+            return Ok(());
+        }
+        let present: HashSet<String> = self
+            .object_spec
+            .properties
+            .iter()
+            .filter(|p| self.src_rows.iter().any(|r| lookup_value(r, p).is_some()))
+            .cloned()
+            .collect();
+        let Some(first) = &self.first_page_properties else {
+            self.first_page_properties = Some(present);
+            return Ok(());
+        };
+
+        let mut added: Vec<&String> = present.difference(first).collect();
+        let mut dropped: Vec<&String> = first.difference(&present).collect();
+        if added.is_empty() && dropped.is_empty() {
+            return Ok(());
+        }
+        added.sort();
+        dropped.sort();
+        let message = format!(
+            "page {} of {} differs from the first page, properties added: [{}], dropped: [{}]",
+            self.pages_fetched,
+            self.object_label,
+            added.iter().map(|p| p.as_str()).collect::<Vec<_>>().join(", "),
+            dropped.iter().map(|p| p.as_str()).collect::<Vec<_>>().join(", ")
+        );
+        match mode {
+            SchemaDriftMode::Warn => {
+                utils::report_warning(&message);
+                Ok(())
+            }
+            SchemaDriftMode::Error => Err(message),
+        }
+    }
+
+    // Replace each buffered record with a row per associated record of
+    // `target`, which is put under `associated` with its properties batch
+    // read. Records without associations drop out, like an inner join
+    fn unnest_associations(&mut self, target: &str) -> Result<(), FdwError> {
+        let associated_ids = |src_row: &JsonValue| {
+            let mut ids: Vec<String> = Vec::new();
+            let results = src_row
+                .get("associations")
+                .and_then(|v| v.get(target))
+                .and_then(|v| v.get("results"))
+                .and_then(|v| v.as_array());
+            for result in results.into_iter().flatten() {
+                // associations of several types repeat the same record
+                if let Some(id) = result.get("id").and_then(|v| v.as_str()) {
+                    if !ids.iter().any(|i| i == id) {
+                        ids.push(id.to_owned());
+                    }
+                }
+            }
+            ids
+        };
+
+        let mut rows = Vec::new();
+        let mut read_ids = Vec::new();
+        let mut seen = HashSet::new();
+        for src_row in std::mem::take(&mut self.src_rows) {
+            let mut ids = associated_ids(&src_row);
+            if ids.len() > self.max_associations {
+                utils::report_warning(&format!(
+                    "record {} has {} associated {}, only the first {} are included",
+                    src_row.get("id").and_then(|v| v.as_str()).unwrap_or_default(),
+                    ids.len(),
+                    target,
+                    self.max_associations
+                ));
+                ids.truncate(self.max_associations);
+            }
+            read_ids.extend(ids.iter().filter(|id| seen.insert(id.to_string())).cloned());
+            rows.push((src_row, ids));
+        }
+
+        let mut associated: HashMap<String, JsonValue> = HashMap::new();
+        if !self.association_properties.is_empty() {
+            for chunk in read_ids.chunks(ASSOCIATION_BATCH_SIZE) {
+                let inputs: Vec<JsonValue> =
+                    chunk.iter().map(|id| serde_json::json!({ "id": id })).collect();
+                let body = serde_json::json!({
+                    "inputs": inputs,
+                    "properties": self.association_properties,
+                });
+                let req = http::Request {
+                    method: http::Method::Post,
+                    url: format!(
+                        "{}/crm/v3/objects/{}/batch/read",
+                        self.base_url,
+                        url_encode(target)
+                    ),
+                    headers: self.headers(),
+                    body: body.to_string(),
+                };
+                let resp_json = self.make_request(&req)?;
+                let results = resp_json.get("results").and_then(|v| v.as_array());
+                for record in results.into_iter().flatten() {
+                    if let Some(id) = record.get("id").and_then(|v| v.as_str()) {
+                        associated.insert(id.to_owned(), record.clone());
+                    }
+                }
+            }
+        }
+
+        let labels = if self.fetch_association_labels {
+            let from_ids: Vec<String> = rows
+                .iter()
+                .filter_map(|(src_row, _)| src_row.get("id")?.as_str().map(String::from))
+                .collect();
+            self.fetch_association_labels(target, &from_ids)?
+        } else {
+            HashMap::new()
+        };
+
+        for (src_row, ids) in rows {
+            let from_id = src_row.get("id").and_then(|v| v.as_str()).unwrap_or_default();
+            for id in ids {
+                let mut row = src_row.clone();
+                row["associated"] = associated
+                    .get(&id)
+                    .cloned()
+                    .unwrap_or_else(|| serde_json::json!({ "id": id }));
+                if let Some(label) = labels.get(&(from_id.to_owned(), id)) {
+                    row["association_label"] = JsonValue::String(label.clone());
+                }
+                self.src_rows.push(row);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Read the labels of the records' associations with `target` from the v4
+    // associations API, keyed by (record id, associated id). Several labels
+    // are joined with `;`, and unlabeled associations are left out
+    fn fetch_association_labels(
+        &mut self,
+        target: &str,
+        from_ids: &[String],
+    ) -> Result<HashMap<(String, String), String>, FdwError> {
+        let mut labels = HashMap::new();
+        for chunk in from_ids.chunks(ASSOCIATION_BATCH_SIZE) {
+            let inputs: Vec<JsonValue> =
+                chunk.iter().map(|id| serde_json::json!({ "id": id })).collect();
+            let req = http::Request {
+                method: http::Method::Post,
+                url: format!(
+                    "{}/crm/v4/associations/{}/{}/batch/read",
+                    self.base_url,
+                    url_encode(&self.object),
+                    url_encode(target)
+                ),
+                headers: self.headers(),
+                body: serde_json::json!({ "inputs": inputs }).to_string(),
+            };
+            let resp_json = self.make_request(&req)?;
+            let results = resp_json.get("results").and_then(|v| v.as_array());
+            for result in results.into_iter().flatten() {
+                let from_id = result.pointer("/from/id").and_then(|v| v.as_str());
+                let to = result.get("to").and_then(|v| v.as_array());
+                let Some(from_id) = from_id else {
+                    continue;
+                };
+                for association in to.into_iter().flatten() {
+                    // ids come as numbers in the v4 API
+                    let to_id = match association.get("toObjectId") {
+                        Some(JsonValue::String(id)) => id.clone(),
+                        Some(JsonValue::Number(id)) => id.to_string(),
+                        _ => continue,
+                    };
+                    let names: Vec<&str> = association
+                        .get("associationTypes")
+                        .and_then(|v| v.as_array())
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|t| t.get("label")?.as_str())
+                        .collect();
+                    if !names.is_empty() {
+                        labels.insert((from_id.to_owned(), to_id), names.join(";"));
+                    }
+                }
+            }
+        }
+        Ok(labels)
+    }
+
+    // set up the scan of one of the table's objects and fetch its first page
+    fn begin_object(&mut self, ctx: &impl ScanSource, object: &str) -> FdwResult {
+        let opts = ctx.table_options();
+        let object = object.to_owned();
+        self.object = object.clone();
+        self.base_url = self.table_base_url(&opts)?;
+        let alias = opts.get("object_alias");
+        self.object_label = match &alias {
+            Some(alias) => format!("table '{}' (object '{}')", alias, object),
+            None => format!("object '{}'", object),
+        };
+        self.emit_keyset = opts.require_or("emit_keyset", "false") == "true";
+        self.object_spec = ObjectSpec::new(&object, &opts, self.object_schemas.as_deref())
+            .map_err(|e| match &alias {
+                Some(alias) => format!("{}: {}", alias, e),
+                None => e,
+            })?;
+        if let Some(results_path) = opts.get("results_path") {
+            self.object_spec.results_path = results_path;
+        }
+        self.object_spec.results_shape = parse_option(&opts, "results_shape", ResultsShape::Auto)?;
+        self.object_spec.extra_params = opts.get("extra_params").filter(|p| !p.is_empty());
+        if let Some(template) = opts.get("endpoint_template") {
+            self.object_spec.endpoint = render_endpoint_template(&template, &object, &opts)?;
+        }
+        self.json_sort_keys = opts.require_or("json_sort_keys", "false") == "true";
+        self.preserve_number_text = opts.require_or("preserve_number_text", "false") == "true";
+        self.lowercase_property_keys =
+            opts.require_or("lowercase_property_keys", "false") == "true";
+        self.json_null_literal = match opts.require_or("json_null_mode", "sql_null").as_str() {
+            "sql_null" => false,
+            "json_null" => true,
+            mode => return Err(format!("invalid json_null_mode '{}'", mode)),
+        };
+        self.on_conversion_error =
+            parse_option(&opts, "on_conversion_error", ConversionErrorMode::Error)?;
+        self.error_sample_limit = match opts.get("error_sample_limit") {
+            Some(_) => Some(parse_option(&opts, "error_sample_limit", 0)?),
+            None => None,
+        };
+        self.skip_incomplete_rows = opts.require_or("skip_incomplete_rows", "false") == "true";
+        self.required_properties = parse_list(&opts, "required_properties");
+        self.property_map = parse_column_map(&opts, "property_map")?;
+        self.normalizations = parse_column_map(&opts, "normalize")?
+            .into_iter()
+            .map(|(column, normalization)| normalization.parse().map(|n| (column, n)))
+            .collect::<Result<_, _>>()?;
+        self.bool_text_formats = parse_column_map(&opts, "bool_text_format")?
+            .into_iter()
+            .map(|(column, format)| format.parse().map(|format| (column, format)))
+            .collect::<Result<_, _>>()?;
+        self.value_kinds = parse_column_map(&opts, "hs_value_kind")?
+            .into_iter()
+            .map(|(column, kind)| kind.parse().map(|kind| (column, kind)))
+            .collect::<Result<_, _>>()?;
+        if self.skip_incomplete_rows && self.required_properties.is_empty() {
+            return Err(
+                "option 'required_properties' is required when 'skip_incomplete_rows' is enabled"
+                    .to_string(),
+            );
+        }
+
+        self.raw_mode = parse_option(&opts, "raw_mode", RawMode::Off)?;
+        if self.raw_mode != RawMode::Off {
+            for col in &self.columns {
+                if !matches!(col.type_oid, TypeOid::Json) {
+                    return Err(format!("raw_mode column '{}' must be of type jsonb", col.name));
+                }
+            }
+        }
+
+        self.provenance_column = opts.get("provenance_column");
+        if let Some(name) = &self.provenance_column {
+            let column = self.columns.iter().find(|c| &c.name == name);
+            if column.is_some_and(|c| !matches!(c.type_oid, TypeOid::Json)) {
+                return Err(format!("provenance column '{}' must be of type jsonb", name));
+            }
+        }
+
+        // Postgres arrays aren't supported by the host, so multi-value
+        // columns are jsonb arrays
+        self.multi_value_columns = parse_list(&opts, "multi_value_columns");
+        for col in &self.columns {
+            if self.multi_value_columns.contains(&col.name)
+                && !matches!(col.type_oid, TypeOid::Json)
+            {
+                return Err(format!("multi-value column '{}' must be of type jsonb", col.name));
+            }
+        }
+
+        self.history_properties = parse_list(&opts, "properties_with_history");
+        if opts.require_or("lifecycle_history", "false") == "true"
+            && !self.history_properties.iter().any(|p| p == "lifecyclestage")
+        {
+            self.history_properties.push("lifecyclestage".to_owned());
+        }
+        self.history_limit = match opts.get("history_limit") {
+            Some(_) => Some(parse_option(&opts, "history_limit", 0)?),
+            None => None,
+        };
+        if self.object_spec.revisions && self.history_properties.is_empty() {
+            return Err(
+                "option 'properties_with_history' is required for revisions".to_string()
+            );
+        }
+        if !self.history_properties.is_empty()
+            && !self.object_spec.crm
+            && !self.object_spec.revisions
+        {
+            return Err(format!("property history is not available for {}", self.object_label));
+        }
+
+        // associated record ids are exposed as `associations.<target>.results`
+        self.association_targets = parse_list(&opts, "associations");
+        if !self.association_targets.is_empty() && !self.object_spec.crm {
+            return Err(format!("associations are not available for {}", self.object_label));
+        }
+
+        self.max_empty_pages = parse_option(&opts, "max_empty_pages", DEFAULT_MAX_EMPTY_PAGES)?;
+        if self.max_empty_pages == 0 {
+            return Err("max_empty_pages must be greater than 0".to_string());
+        }
+        self.schema_drift = match opts.get("schema_drift") {
+            Some(mode) => Some(mode.parse()?),
+            None => None,
+        };
+
+        // associations only counted still need fetching
+        for target in parse_list(&opts, "association_counts") {
+            if !self.object_spec.crm {
+                return Err(format!("associations are not available for {}", self.object_label));
+            }
+            if !self.association_targets.contains(&target) {
+                self.association_targets.push(target);
+            }
+        }
+
+        // one row per associated record, as `associated.id` and
+        // `associated.properties.<property>`, next to the record's own columns
+        self.unnest_target = opts.get("unnest_associations");
+        self.association_properties = parse_list(&opts, "association_properties");
+        self.max_associations =
+            parse_option(&opts, "max_associations", DEFAULT_MAX_ASSOCIATIONS)?;
+        self.fetch_association_labels = self.unnest_target.is_some()
+            && self.columns.iter().any(|c| c.name == "association_label");
+        if let Some(target) = &self.unnest_target {
+            if !self.object_spec.crm {
+                return Err(format!(
+                    "unnest_associations is not available for {}",
+                    self.object_label
+                ));
+            }
+            if !self.association_targets.contains(target) {
+                self.association_targets.push(target.clone());
+            }
+        }
+
+        self.id_only = opts.require_or("id_only", "false") == "true";
+        if self.id_only {
+            if !self.object_spec.crm {
+                return Err(format!("id_only is not available for {}", self.object_label));
+            }
+            // without a properties parameter HubSpot sends its defaults, so
+            // ask for the id property alone
+            self.object_spec.properties = vec!["hs_object_id".to_owned()];
+            self.history_properties.clear();
+        }
+
+        // show enumeration properties, such as hs_object_source and other
+        // source attribution fields, by their labels
+        self.enum_labels.clear();
+        if opts.require_or("enum_labels", "false") == "true" {
+            if !self.object_spec.crm {
+                return Err(format!("enum_labels is not available for {}", self.object_label));
+            }
+            self.fetch_enum_labels()?;
+        }
+
+        // push the selected columns down as the properties to fetch, using
+        // the search endpoint when the list is too long to fit in the URL
+        self.use_search = false;
+        self.search_abandoned = false;
+        self.selected_properties.clear();
+        if self.object_spec.crm && !self.id_only {
+            let columns: Vec<String> = self
+                .columns
+                .iter()
+                .flat_map(|c| match &self.provenance_column {
+                    Some(name) if *name == c.name => {
+                        SOURCE_PROPERTIES.iter().map(|p| p.to_string()).collect()
+                    }
+                    _ => vec![self.source_name(&c.name).to_owned()],
+                })
+                .collect();
+            let mut properties = projected_properties(&columns, &self.history_properties);
+            if self.lowercase_property_keys {
+                let mut seen = HashSet::new();
+                properties = properties
+                    .into_iter()
+                    .map(|p| p.to_lowercase())
+                    .filter(|p| seen.insert(p.clone()))
+                    .collect();
+            }
+            if !properties.is_empty() {
+                self.selected_properties = properties.clone();
+                self.object_spec.properties = properties;
+            } else if !columns.is_empty()
+                && opts.require_or("warn_on_default_properties", "false") == "true"
+            {
+                let defaults = if self.object_spec.properties.is_empty() {
+                    "HubSpot's default properties".to_owned()
+                } else {
+                    format!("default properties {}", self.object_spec.properties.join(", "))
+                };
+                utils::report_warning(&format!(
+                    "none of the selected columns of {} is a HubSpot property, so {} are \
+                     fetched; set id_only 'true' to fetch only ids",
+                    self.object_label, defaults
+                ));
+            }
+
+            // measure the first request the list endpoint would get
+            self.after = None;
+            self.page_size = self
+                .object_spec
+                .page_size_cap(false, !self.history_properties.is_empty());
+            if self.list_request().url.len() > MAX_URL_LENGTH {
+                if !self.history_properties.is_empty() || !self.association_targets.is_empty() {
+                    return Err(
+                        "too many properties selected to fetch alongside property history \
+                         or associations"
+                            .to_string(),
+                    );
+                }
+                self.use_search = true;
+            }
+        }
+
+        self.cdc_watermark = match opts.get("cdc_watermark") {
+            Some(watermark) => Some(
+                time::parse_from_rfc3339(&watermark)
+                    .map_err(|e| format!("invalid cdc_watermark '{}': {}", watermark, e))?,
+            ),
+            None => None,
+        };
+
+        // only the search endpoint can filter by owner or modification time,
+        // the latter counted back from now in epoch millis
+        self.owner_id = opts.get("owner_id");
+        self.modified_since = match opts.get("modified_within_minutes") {
+            Some(_) => match parse_option::<i64>(&opts, "modified_within_minutes", 0)? {
+                minutes if minutes <= 0 => {
+                    return Err("modified_within_minutes must be greater than 0".to_string())
+                }
+                minutes => Some((time::epoch_secs() - minutes * 60) * 1000),
+            },
+            None => None,
+        };
+        // as can it sort, here by id so scans come back in a reproducible order
+        self.stable_order = opts.require_or("stable_order", "false") == "true";
+        let search_options = [
+            ("owner_id", self.owner_id.is_some()),
+            ("modified_within_minutes", self.modified_since.is_some()),
+            ("stable_order", self.stable_order),
+        ];
+        for (option, _) in search_options.iter().filter(|(_, set)| *set) {
+            if !self.object_spec.crm {
+                return Err(format!("{} is not available for {}", option, self.object_label));
+            }
+            if !self.history_properties.is_empty() || !self.association_targets.is_empty() {
+                return Err(format!(
+                    "{} can't be combined with property history or associations",
+                    option
+                ));
+            }
+            self.use_search = true;
+        }
+
+        // with qual_pushdown, WHERE clauses on properties go to search as
+        // filters, alongside the window and option filters
+        self.qual_filters.clear();
+        if opts.require_or("qual_pushdown", "false") == "true"
+            && self.object_spec.crm
+            && self.history_properties.is_empty()
+            && self.association_targets.is_empty()
+        {
+            let fixed_filters = 2
+                + usize::from(self.owner_id.is_some())
+                + usize::from(self.modified_since.is_some());
+            self.qual_filters = self.translate_quals(
+                &ctx.quals(),
+                SEARCH_MAX_FILTERS.saturating_sub(fixed_filters),
+            );
+            if !self.qual_filters.is_empty() {
+                self.use_search = true;
+            }
+        }
+
+        // Search is only used when something above needs it, as listing is
+        // cheaper and has no result cap. force_endpoint overrides the choice
+        match opts.require_or("force_endpoint", "auto").as_str() {
+            "auto" => {}
+            "list" if !self.use_search => {}
+            "list" => {
+                // Postgres still applies the quals which aren't pushed down
+                self.qual_filters.clear();
+                if !self.can_list() {
+                    return Err(
+                        "force_endpoint 'list' can't be used with owner_id, \
+                         modified_within_minutes, stable_order or properties too long for \
+                         the URL"
+                            .to_string(),
+                    );
+                }
+                self.use_search = false;
+            }
+            "search" => {
+                if !self.object_spec.crm {
+                    return Err(format!("search is not available for {}", self.object_label));
+                }
+                if !self.history_properties.is_empty() || !self.association_targets.is_empty() {
+                    return Err(
+                        "force_endpoint 'search' can't be combined with property history or \
+                         associations"
+                            .to_string(),
+                    );
+                }
+                self.use_search = true;
+            }
+            endpoint => return Err(format!("invalid force_endpoint '{}'", endpoint)),
+        }
+
+        self.auto_search_fallback = opts.require_or("auto_search_fallback", "false") == "true";
+        if self.auto_search_fallback && !self.object_spec.crm {
+            return Err(format!("auto_search_fallback is not available for {}", self.object_label));
+        }
+
+        // request full pages unless told otherwise, within what the endpoint
+        // accepts
+        let page_size_cap = self
+            .object_spec
+            .page_size_cap(self.use_search, !self.history_properties.is_empty());
+        let page_size: Option<u32> = match opts.get("page_size") {
+            Some(_) => match parse_option(&opts, "page_size", 0)? {
+                0 => return Err("page_size must be greater than 0".to_string()),
+                size => Some(size),
+            },
+            None => None,
+        };
+        self.page_size = match (page_size_cap, page_size) {
+            (Some(cap), Some(size)) if size > cap => {
+                utils::report_warning(&format!(
+                    "page_size {} exceeds the maximum of {} for this endpoint, using {}",
+                    size, cap, cap
+                ));
+                Some(cap)
+            }
+            (Some(cap), size) => Some(size.unwrap_or(cap)),
+            (None, Some(_)) => {
+                utils::report_warning(&format!(
+                    "page_size is not supported for {} and is ignored",
+                    self.object_label
+                ));
+                None
+            }
+            (None, None) => None,
+        };
+
+        // searches with more results than can be paged through are split
+        // into date windows on this property, the creation date by default
+        let (created, modified) =
+            (self.object_spec.created_property, self.object_spec.modified_property);
+        self.partition_property = opts.require_or("partition_property", created);
+        if self.partition_property != created && self.partition_property != modified {
+            return Err(format!(
+                "partition_property must be '{}' or '{}' for {}, got '{}'",
+                created, modified, self.object_label, self.partition_property
+            ));
+        }
+
+        self.reset_paging();
+        self.fetch_data()?;
+        self.src_idx = 0;
+        utils::report_info(&format!("Initial fetch complete. Row count: {}", self.src_rows.len()));
+
+        Ok(())
+    }
+
+    // set up the instance from the server options
+    fn configure_server(&mut self, opts: &impl OptionSource) -> FdwResult {
+        if let Some(url) = parse_api_url(opts)? {
+            self.server_base_url = url;
+        }
+        self.base_url = self.server_base_url.clone();
+        // tables could otherwise send the server's token to any host
+        self.allow_table_api_url = opts.require_or("allow_table_api_url", "false") == "true";
+
+        // expired OAuth access tokens are refreshed when all the refresh
+        // credentials are given
+        self.oauth_refresh = match (
+            opts.get("refresh_token"),
+            opts.get("client_id"),
+            opts.get("client_secret"),
+        ) {
+            (Some(refresh_token), Some(client_id), Some(client_secret)) => Some(OAuthRefresh {
+                refresh_token,
+                client_id,
+                client_secret,
+            }),
+            _ => None,
+        };
+        // Sharing refreshed tokens between workers is opt-in, as the token is
+        // stored in plaintext in the stats metadata, where anyone who can
+        // read the wrappers stats table can use it until it expires
+        self.persist_refreshed_token =
+            opts.require_or("persist_refreshed_token", "false") == "true";
+
+        // private app tokens and OAuth access tokens are both bearer tokens,
+        // and with refresh credentials the access token can be left out
+        self.api_key = match (opts.get("access_token"), &self.oauth_refresh) {
+            (Some(access_token), _) => access_token,
+            (None, Some(_)) => {
+                self.token_expires_at = Some(0);
+                String::default()
+            }
+            (None, None) => opts.require("api_key")?,
+        };
+        if self.oauth_refresh.is_some() && self.persist_refreshed_token {
+            self.load_persisted_token();
+        }
+        self.max_retries = parse_option(opts, "max_retries", 3)?;
+        self.retry_on_5xx = parse_option(opts, "retry_on_5xx", true)?;
+        self.debug_plan = opts.require_or("debug_plan", "false") == "true";
+        let status_codes = |name: &str| {
+            parse_list(opts, name)
+                .iter()
+                .map(|code| {
+                    code.parse()
+                        .map_err(|_| format!("invalid status code '{}' in {}", code, name))
+                })
+                .collect::<Result<Vec<u16>, _>>()
+        };
+        if opts.get("http_retry_status_codes").is_some() {
+            self.retry_status_codes = status_codes("http_retry_status_codes")?;
+        }
+        if opts.get("write_retry_status_codes").is_some() {
+            self.write_retry_status_codes = status_codes("write_retry_status_codes")?;
+        }
+        self.rate_limit_per_sec = match opts.get("rate_limit_per_sec") {
+            Some(_) => match parse_option(opts, "rate_limit_per_sec", 0)? {
+                0 => return Err("rate_limit_per_sec must be greater than 0".to_string()),
+                rate_limit => Some(rate_limit),
+            },
+            None => None,
+        };
+        self.scan_deadline_ms = match opts.get("scan_deadline_ms") {
+            Some(_) => match parse_option(opts, "scan_deadline_ms", 0)? {
+                0 => return Err("scan_deadline_ms must be greater than 0".to_string()),
+                deadline_ms => Some(deadline_ms),
+            },
+            None => None,
+        };
+
+        // fetch the schema list once per server so table objects can be
+        // checked against it, which catches custom object id typos early
+        if opts.require_or("validate_on_init", "false") == "true" {
+            self.fetch_object_schemas()?;
+        }
+
+        Ok(())
+    }
+
+    fn start_scan(&mut self, ctx: &impl ScanSource) -> FdwResult {
+        // a failed modify never reaches end_modify
+        self.in_modify = false;
+        self.src_rows.clear();
+        self.src_idx = 0;
+        self.skipped_rows = 0;
+        self.warnings_logged = 0;
+        self.warnings_suppressed = 0;
+        self.rows_emitted = 0;
+        self.last_id = None;
+        self.last_updated_at = None;
+        self.scan_started_ms = Some(time::epoch_secs() * 1000);
+
+        // several comma-separated objects are scanned one after another
+        self.columns = ctx.columns();
+        let opts = ctx.table_options();
+        opts.require("object")?;
+        self.objects = parse_list(&opts, "object");
+        self.pending_objects = self.objects.iter().skip(1).rev().cloned().collect();
+        let object = self.objects.first().cloned().ok_or("option 'object' is empty")?;
+        check_option_conflicts(&opts)?;
+
+        // `LIMIT 0` or quals which can't all hold need no requests at all
+        self.short_circuited = opts.require_or("short_circuit_empty_scans", "true") == "true"
+            && (ctx.limit() == Some(0) || contradictory_quals(&ctx.quals()));
+        if self.short_circuited {
+            utils::report_info(&format!(
+                "Scan of {} can't return any rows, skipping its requests",
+                object
+            ));
+            self.pending_objects.clear();
+            self.has_more = false;
+            self.emit_keyset = false;
+            return Ok(());
+        }
+
+        self.begin_object(ctx, &object)
+    }
+
+    // the scan's next row, fetching further pages and objects as needed
+    fn next_row(&mut self, ctx: &impl ScanSource) -> Result<Option<Vec<Option<Cell>>>, FdwError> {
+        if self.short_circuited {
+            return Ok(None);
+        }
+
+        loop {
+            if self.src_idx >= self.src_rows.len() {
+                // If we have more data to fetch, get the next page
+                if self.has_more {
+                    self.fetch_data()?;
+                    self.src_idx = 0;
+                    continue;
+                }
+
+                // then move on to the table's next object, if any
+                if let Some(object) = self.pending_objects.pop() {
+                    self.begin_object(ctx, &object)?;
+                    continue;
+                }
+                return Ok(None);
+            }
+
+            // Skip records which lack any of the required properties
+            let src_row = &self.src_rows[self.src_idx];
+            if self.skip_incomplete_rows
+                && self.raw_mode == RawMode::Off
+                && !self
+                    .required_properties
+                    .iter()
+                    .all(|p| has_property(src_row, p))
+            {
+                self.skipped_rows += 1;
+                self.src_idx += 1;
+                continue;
+            }
+
+            let (cells, failures) = self.convert_row(src_row, &self.columns)?;
+            self.src_idx += 1;
+
+            for (col_name, error) in &failures {
+                if self
+                    .error_sample_limit
+                    .is_some_and(|limit| self.warnings_logged >= limit)
+                {
+                    self.warnings_suppressed += 1;
+                    continue;
+                }
+                self.warnings_logged += 1;
+                utils::report_warning(&format!(
+                    "{} (column '{}' of {})",
+                    error, col_name, self.object_label
+                ));
+            }
+
+            // rows dropped for failed conversions only show up in warnings
+            if !failures.is_empty() && self.on_conversion_error == ConversionErrorMode::SkipRow {
+                continue;
+            }
+
+            self.rows_emitted += 1;
+            if self.emit_keyset {
+                let src_row = &self.src_rows[self.src_idx - 1];
+                let field = |name: &str| lookup_value(src_row, name)?.as_str().map(String::from);
+                self.last_id = field("id");
+                self.last_updated_at = field("updatedAt").or_else(|| field("hs_lastmodifieddate"));
+            }
+            return Ok(Some(cells));
+        }
+    }
+
+    fn restart_scan(&mut self, ctx: &impl ScanSource) -> FdwResult {
+        self.src_idx = 0;
+        if self.short_circuited {
+            return Ok(());
+        }
+
+        // tables over several objects start again from the first
+        if self.objects.len() > 1 {
+            self.pending_objects = self.objects.iter().skip(1).rev().cloned().collect();
+            let object = self.objects[0].clone();
+            return self.begin_object(ctx, &object);
+        }
+
+        // only the current page is buffered, so earlier ones are re-fetched
+        if self.pages_fetched > 1 {
+            self.reset_paging();
+            self.fetch_data()?;
+        }
+        Ok(())
+    }
+
+    fn finish_scan(&mut self) {
+        // machine-readable position for orchestrators resuming an export,
+        // logged as `keyset: {...}`. Resume by fetching from `after` (the
+        // cursor of the page holding the last row) within `search_window`
+        // if set, and skipping records up to and including `last_id`
+        if self.emit_keyset {
+            let keyset = serde_json::json!({
+                "object": self.object,
+                "after": self.page_cursor,
+                "search_window": self.search_window,
+                "last_id": self.last_id,
+                "last_updated_at": self.last_updated_at,
+                "rows": self.rows_emitted,
+                "complete": !self.has_more && self.src_idx >= self.src_rows.len(),
+            });
+            utils::report_info(&format!("keyset: {}", keyset));
+        }
+
+        if self.warnings_suppressed > 0 {
+            utils::report_warning(&format!(
+                "... {} more conversion warnings suppressed",
+                self.warnings_suppressed
+            ));
+        }
+        if self.skipped_rows > 0 {
+            utils::report_info(&format!(
+                "Skipped {} rows missing required properties",
+                self.skipped_rows
+            ));
+        }
+        self.src_rows.clear();
+        self.src_idx = 0;
+        self.after = None;
+        self.has_more = false;
+        self.scan_started_ms = None;
+    }
+
+    // Records are otherwise read-only, but tables can opt in to specific
+    // write operations through `write_operations`
+    fn start_modify(&mut self, opts: &impl OptionSource) -> FdwResult {
+        self.write_operations = parse_list(opts, "write_operations");
+        if self.write_operations.is_empty() {
+            return Err("This FDW is read-only".to_string());
+        }
+        for operation in &self.write_operations {
+            if operation != "merge" && operation != "gdpr_delete" {
+                return Err(format!("unsupported write operation '{}'", operation));
+            }
+        }
+
+        let object = opts.require("object")?;
+        self.base_url = self.table_base_url(opts)?;
+        self.object_spec = ObjectSpec::new(&object, opts, self.object_schemas.as_deref())?;
+        if !self.object_spec.crm {
+            return Err(format!("write operations are not available for object '{}'", object));
+        }
+        self.object = object;
+
+        // GDPR deletes can't be undone, so they need a guard of their own
+        if self.write_operations.iter().any(|o| o == "gdpr_delete") {
+            if opts.require_or("gdpr_delete", "false") != "true" {
+                return Err("write operation 'gdpr_delete' permanently deletes contacts and \
+                            requires the option gdpr_delete 'true'"
+                    .to_string());
+            }
+            if self.object_spec.endpoint != "/crm/v3/objects/contacts" {
+                return Err(format!(
+                    "GDPR deletes are only available for contacts, not '{}'",
+                    self.object
+                ));
+            }
+        }
+
+        self.in_modify = true;
+        Ok(())
+    }
+
+    // `UPDATE ... SET _merge_into = '<primary id>' WHERE id = '<id>'` merges
+    // the record into the primary one, when merges are allowed
+    fn update_row(&mut self, rowid: &Cell, cells: &[(String, Option<Cell>)]) -> FdwResult {
+        let merge_into = cells
+            .iter()
+            .find(|(col, _)| col == "_merge_into")
+            .and_then(|(_, cell)| cell.as_ref());
+        let merge_allowed = self.write_operations.iter().any(|o| o == "merge");
+        let Some(merge_into) = merge_into.filter(|_| merge_allowed) else {
+            return Err("only merges are supported, by setting _merge_into".to_string());
+        };
+
+        let body = serde_json::json!({
+            "primaryObjectId": cell_to_id(merge_into)?,
+            "objectIdToMerge": cell_to_id(rowid)?,
+        });
+        let req = http::Request {
+            method: http::Method::Post,
+            url: format!("{}{}/merge", self.base_url, self.object_spec.endpoint),
+            headers: self.headers(),
+            body: body.to_string(),
+        };
+        self.make_request(&req)?;
+
+        Ok(())
+    }
+
+    // `DELETE` permanently deletes contacts through HubSpot's GDPR endpoint,
+    // there is no archiving delete
+    fn delete_row(&mut self, rowid: &Cell) -> FdwResult {
+        if !self.write_operations.iter().any(|o| o == "gdpr_delete") {
+            return Err("This FDW is read-only, only GDPR deletes of contacts are supported \
+                        through write_operations 'gdpr_delete'"
+                .to_string());
+        }
+
+        let body = serde_json::json!({ "objectId": cell_to_id(rowid)? });
+        let req = http::Request {
+            method: http::Method::Post,
+            url: format!("{}{}/gdpr-delete", self.base_url, self.object_spec.endpoint),
+            headers: self.headers(),
+            body: body.to_string(),
+        };
+        self.make_request(&req)?;
+
+        Ok(())
+    }
+}
+
+impl Guest for HubspotFdw {
+    fn host_version_requirement() -> String {
+        "^0.1.0".to_string()
+    }
+
+    fn init(ctx: &Context) -> FdwResult {
+        Self::init_instance();
+        Self::this_mut().configure_server(&ctx.get_options(OptionsType::Server))
+    }
+
+    fn begin_scan(ctx: &Context) -> FdwResult {
+        Self::this_mut().start_scan(ctx)
+    }
+
+    fn iter_scan(ctx: &Context, row: &Row) -> Result<Option<u32>, FdwError> {
+        let Some(cells) = Self::this_mut().next_row(ctx)? else {
+            return Ok(None);
+        };
+        for cell in &cells {
+            row.push(cell.as_ref());
+        }
+        Ok(Some(1))
+    }
+
+    fn re_scan(ctx: &Context) -> FdwResult {
+        Self::this_mut().restart_scan(ctx)
+    }
+
+    fn end_scan(_ctx: &Context) -> FdwResult {
+        Self::this_mut().finish_scan();
+        Ok(())
+    }
+
+    fn begin_modify(ctx: &Context) -> FdwResult {
+        Self::this_mut().start_modify(&ctx.get_options(OptionsType::Table))
+    }
+
+    fn insert(_ctx: &Context, _row: &Row) -> FdwResult {
+        Err("This FDW is read-only".to_string())
+    }
+
+    fn update(_ctx: &Context, rowid: Cell, row: &Row) -> FdwResult {
+        let cells: Vec<_> = row.cols().into_iter().zip(row.cells()).collect();
+        Self::this_mut().update_row(&rowid, &cells)
+    }
+
+    fn delete(_ctx: &Context, rowid: Cell) -> FdwResult {
+        Self::this_mut().delete_row(&rowid)
+    }
+
+    fn end_modify(_ctx: &Context) -> FdwResult {
+        Self::this_mut().in_modify = false;
+        Ok(())
+    }
+}
+
+bindings::export!(HubspotFdw with_types_in bindings);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // stand-ins for the host interfaces, recording requests and messages
+    // and answering with queued responses, per test thread
+    pub(crate) mod host {
+        use std::cell::RefCell;
+        use std::collections::{HashMap, VecDeque};
+
+        thread_local! {
+            static RESPONSES: RefCell<VecDeque<http::HttpResult>> = RefCell::default();
+            static REQUESTS: RefCell<Vec<http::Request>> = RefCell::default();
+            static SENT_AT: RefCell<Vec<i64>> = RefCell::default();
+            static CLOCK_MS: RefCell<i64> = const { RefCell::new(1_700_000_000_000) };
+            static MESSAGES: RefCell<Vec<String>> = RefCell::default();
+            static METADATA: RefCell<HashMap<String, String>> = RefCell::default();
+        }
+
+        pub fn response(status_code: u16, body: &str) -> http::Response {
+            http::Response {
+                url: String::new(),
+                status_code,
+                headers: vec![("content-type".to_owned(), "application/json".to_owned())],
+                body: body.to_owned(),
+            }
+        }
+
+        // queue a JSON response for the next request
+        pub fn respond(status_code: u16, body: &str) {
+            respond_with(response(status_code, body));
+        }
+
+        pub fn respond_with(resp: http::Response) {
+            RESPONSES.with(|r| r.borrow_mut().push_back(Ok(resp)));
+        }
+
+        // let time pass on the host clock
+        pub fn advance(millis: u64) {
+            time::sleep(millis);
+        }
+
+        pub fn requests() -> Vec<http::Request> {
+            REQUESTS.with(|r| r.borrow().clone())
+        }
+
+        // when each of `requests()` was sent, in epoch seconds
+        pub fn request_times() -> Vec<i64> {
+            SENT_AT.with(|t| t.borrow().clone())
+        }
+
+        // info and warning messages, prefixed with their level
+        pub fn messages() -> Vec<String> {
+            MESSAGES.with(|m| m.borrow().clone())
+        }
+
+        pub mod http {
+            pub use crate::bindings::supabase::wrappers::http::{
+                Headers, HttpResult, Method, Request, Response,
+            };
+
+            fn send(req: &Request) -> HttpResult {
+                super::REQUESTS.with(|r| r.borrow_mut().push(req.clone()));
+                super::SENT_AT.with(|t| t.borrow_mut().push(super::time::epoch_secs()));
+                super::RESPONSES
+                    .with(|r| r.borrow_mut().pop_front())
+                    .unwrap_or_else(|| Err(format!("no response queued for {}", req.url)))
+            }
+
+            pub fn get(req: &Request) -> HttpResult {
+                send(req)
+            }
+
+            pub fn post(req: &Request) -> HttpResult {
+                send(req)
+            }
+
+            pub fn put(req: &Request) -> HttpResult {
+                send(req)
+            }
+
+            pub fn patch(req: &Request) -> HttpResult {
+                send(req)
+            }
+
+            pub fn delete(req: &Request) -> HttpResult {
+                send(req)
+            }
+        }
+
+        pub mod time {
+            pub fn epoch_secs() -> i64 {
+                super::CLOCK_MS.with(|c| *c.borrow()).div_euclid(1000)
+            }
+
+            pub fn sleep(millis: u64) {
+                super::CLOCK_MS.with(|c| *c.borrow_mut() += millis as i64);
+            }
+
+            // microseconds since the epoch, for `YYYY-MM-DDTHH:MM:SS[.f]`
+            // followed by `Z` or a `+HH:MM` offset
+            pub fn parse_from_rfc3339(s: &str) -> Result<i64, String> {
+                let invalid = || format!("invalid RFC 3339 datetime '{}'", s);
+                let num = |range: std::ops::Range<usize>| -> Result<i64, String> {
+                    let part = s.get(range).ok_or_else(invalid)?;
+                    if !part.bytes().all(|b| b.is_ascii_digit()) {
+                        return Err(invalid());
+                    }
+                    part.parse().map_err(|_| invalid())
+                };
+                let bytes = s.as_bytes();
+                if bytes.len() < 20
+                    || bytes[4] != b'-'
+                    || bytes[7] != b'-'
+                    || !matches!(bytes[10], b'T' | b't' | b' ')
+                    || bytes[13] != b':'
+                    || bytes[16] != b':'
+                {
+                    return Err(invalid());
+                }
+                let (year, month, day) = (num(0..4)?, num(5..7)?, num(8..10)?);
+                let secs_of_day = num(11..13)? * 3600 + num(14..16)? * 60 + num(17..19)?;
+
+                let mut rest = &s[19..];
+                let mut micros = 0;
+                if let Some(fraction) = rest.strip_prefix('.') {
+                    let digits = fraction.bytes().take_while(|b| b.is_ascii_digit()).count();
+                    if digits == 0 {
+                        return Err(invalid());
+                    }
+                    let padded = format!("{:0<6}", &fraction[..digits.min(6)]);
+                    micros = padded.parse().map_err(|_| invalid())?;
+                    rest = &fraction[digits..];
+                }
+                let offset_secs = match rest {
+                    "Z" | "z" => 0,
+                    _ if rest.len() == 6 && &rest[3..4] == ":" => {
+                        let offset = rest[1..3].parse::<i64>().map_err(|_| invalid())? * 3600
+                            + rest[4..6].parse::<i64>().map_err(|_| invalid())? * 60;
+                        match &rest[..1] {
+                            "+" => offset,
+                            "-" => -offset,
+                            _ => return Err(invalid()),
+                        }
+                    }
+                    _ => return Err(invalid()),
+                };
+
+                // days from the civil date, as in Howard Hinnant's algorithm
+                let y = if month <= 2 { year - 1 } else { year };
+                let era = y.div_euclid(400);
+                let yoe = y - era * 400;
+                let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+                let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+                let days = era * 146_097 + doe - 719_468;
+
+                Ok((days * 86_400 + secs_of_day - offset_secs) * 1_000_000 + micros)
+            }
+        }
+
+        pub mod utils {
+            pub fn report_info(msg: &str) {
+                super::MESSAGES.with(|m| m.borrow_mut().push(format!("info: {}", msg)));
+            }
+
+            pub fn report_warning(msg: &str) {
+                super::MESSAGES.with(|m| m.borrow_mut().push(format!("warning: {}", msg)));
+            }
+        }
+
+        pub mod stats {
+            pub fn get_metadata(fdw_name: &str) -> Option<String> {
+                super::METADATA.with(|m| m.borrow().get(fdw_name).cloned())
+            }
+
+            pub fn set_metadata(fdw_name: &str, metadata: &Option<String>) {
+                super::METADATA.with(|m| match metadata {
+                    Some(metadata) => m.borrow_mut().insert(fdw_name.to_owned(), metadata.clone()),
+                    None => m.borrow_mut().remove(fdw_name),
+                });
+            }
+        }
+    }
+
+    impl OptionSource for HashMap<String, String> {
+        fn get(&self, key: &str) -> Option<String> {
+            HashMap::get(self, key).cloned()
+        }
+    }
+
+    // the scan context Postgres would pass, as plain values
+    #[derive(Default)]
+    struct Scan {
+        options: HashMap<String, String>,
+        columns: Vec<ColumnSpec>,
+        quals: Vec<QualSpec>,
+        limit: Option<i64>,
+    }
+
+    impl ScanSource for Scan {
+        type Options = HashMap<String, String>;
+
+        fn table_options(&self) -> HashMap<String, String> {
+            self.options.clone()
+        }
+
+        fn columns(&self) -> Vec<ColumnSpec> {
+            self.columns.clone()
+        }
+
+        fn quals(&self) -> Vec<QualSpec> {
+            self.quals.clone()
+        }
+
+        fn limit(&self) -> Option<i64> {
+            self.limit
+        }
+    }
+
+    fn options(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    fn column(name: &str, type_oid: TypeOid) -> ColumnSpec {
+        ColumnSpec {
+            name: name.to_owned(),
+            type_oid,
+        }
+    }
+
+    fn scan(table_options: &[(&str, &str)], columns: &[(&str, TypeOid)]) -> Scan {
+        Scan {
+            options: options(table_options),
+            columns: columns.iter().map(|(name, oid)| column(name, *oid)).collect(),
+            ..Scan::default()
+        }
+    }
+
+    // an instance set up with the given server options besides the api key
+    fn fdw_with(server_options: &[(&str, &str)]) -> HubspotFdw {
+        let mut opts = options(server_options);
+        opts.entry("api_key".to_owned()).or_insert_with(|| "key".to_owned());
+        let mut fdw = HubspotFdw::default();
+        fdw.configure_server(&opts).unwrap();
+        fdw
+    }
+
+    fn fdw() -> HubspotFdw {
+        fdw_with(&[])
+    }
+
+    // run the whole scan, collecting its rows
+    fn scan_rows(fdw: &mut HubspotFdw, scan: &Scan) -> Result<Vec<Vec<Option<Cell>>>, FdwError> {
+        fdw.start_scan(scan)?;
+        let mut rows = Vec::new();
+        while let Some(row) = fdw.next_row(scan)? {
+            rows.push(row);
+        }
+        fdw.finish_scan();
+        Ok(rows)
+    }
+
+    // scan a table over a single page holding the given response body
+    fn single_page(
+        table_options: &[(&str, &str)],
+        columns: &[(&str, TypeOid)],
+        body: &str,
+    ) -> Result<Vec<Vec<Option<Cell>>>, FdwError> {
+        host::respond(200, body);
+        scan_rows(&mut fdw(), &scan(table_options, columns))
+    }
+
+    // a cell's value as text, to compare cells which aren't PartialEq
+    fn text(cell: &Option<Cell>) -> Option<String> {
+        Some(match cell.as_ref()? {
+            Cell::Bool(v) => v.to_string(),
+            Cell::I16(v) => v.to_string(),
+            Cell::I32(v) => v.to_string(),
+            Cell::I64(v) => v.to_string(),
+            Cell::F64(v) => v.to_string(),
+            Cell::Numeric(v) => v.to_string(),
+            Cell::String(v) | Cell::Json(v) => v.clone(),
+            Cell::Date(v) | Cell::Timestamp(v) | Cell::Timestamptz(v) => v.to_string(),
+            cell => format!("{:?}", cell),
+        })
+    }
+
+    #[test]
+    fn sort_json_keys_gives_identical_text_for_equal_objects() {
+        let a: JsonValue =
+            serde_json::from_str(r#"{"b": 1, "a": {"d": [{"y": 1, "x": 2}], "c": 2}}"#).unwrap();
+        let b: JsonValue =
+            serde_json::from_str(r#"{"a": {"c": 2, "d": [{"x": 2, "y": 1}]}, "b": 1}"#).unwrap();
+        assert_ne!(a.to_string(), b.to_string());
+        assert_eq!(sort_json_keys(&a).to_string(), sort_json_keys(&b).to_string());
+        assert_eq!(sort_json_keys(&a).to_string(), r#"{"a":{"c":2,"d":[{"x":2,"y":1}]},"b":1}"#);
+    }
+
+    #[test]
+    fn json_sort_keys_applies_to_scanned_json_columns() {
+        let mut fdw = fdw();
+        host::respond(
+            200,
+            r#"{"results": [{"id": "1", "properties": {"b": "2", "a": "1"}}]}"#,
+        );
+        let sorted = scan(
+            &[("object", "contacts"), ("json_sort_keys", "true")],
+            &[("id", TypeOid::String), ("properties", TypeOid::Json)],
+        );
+        let rows = scan_rows(&mut fdw, &sorted).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(text(&rows[0][0]).as_deref(), Some("1"));
+        assert_eq!(text(&rows[0][1]).as_deref(), Some(r#"{"a":"1","b":"2"}"#));
+
+        // without the option keys stay in the order HubSpot sent them
+        host::respond(
+            200,
+            r#"{"results": [{"id": "1", "properties": {"b": "2", "a": "1"}}]}"#,
+        );
+        let unsorted = scan(
+            &[("object", "contacts")],
+            &[("id", TypeOid::String), ("properties", TypeOid::Json)],
+        );
+        let rows = scan_rows(&mut fdw, &unsorted).unwrap();
+        assert_eq!(text(&rows[0][1]).as_deref(), Some(r#"{"b":"2","a":"1"}"#));
+        assert!(host::requests()[0].url.contains("/crm/v3/objects/contacts"));
+        let fetched = "info: Initial fetch complete. Row count: 1".to_owned();
+        assert!(host::messages().contains(&fetched));
+    }
+
+    #[test]
+    fn incomplete_records_are_skipped_and_counted() {
+        let mut fdw = fdw();
+        host::respond(
+            200,
+            r#"{"results": [
+                {"id": "1", "properties": {"email": "a@example.com"}},
+                {"id": "2", "properties": {"email": null}},
+                {"id": "3", "properties": {}}
+            ]}"#,
+        );
+        let scan = scan(
+            &[
+                ("object", "contacts"),
+                ("skip_incomplete_rows", "true"),
+                ("required_properties", "email"),
+            ],
+            &[("id", TypeOid::String), ("email", TypeOid::String)],
+        );
+        let rows = scan_rows(&mut fdw, &scan).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(text(&rows[0][0]).as_deref(), Some("1"));
+        assert_eq!(fdw.skipped_rows, 2);
+        let skipped = "info: Skipped 2 rows missing required properties".to_owned();
+        assert!(host::messages().contains(&skipped));
+    }
+
+    #[test]
+    fn subscriptions_are_read_for_one_email_with_the_recipient() {
+        let mut fdw = fdw();
+        host::respond(
+            200,
+            r#"{"recipient": "a@example.com", "subscriptionStatuses": [
+                {"id": "10", "name": "Newsletter", "status": "SUBSCRIBED"},
+                {"id": "11", "name": "Offers", "status": "NOT_SUBSCRIBED"}
+            ]}"#,
+        );
+        let scan = scan(
+            &[("object", "subscriptions"), ("contact_email", "a+b@example.com")],
+            &[
+                ("id", TypeOid::String),
+                ("status", TypeOid::String),
+                ("recipient", TypeOid::String),
+            ],
+        );
+        let rows = scan_rows(&mut fdw, &scan).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(text(&rows[1][1]).as_deref(), Some("NOT_SUBSCRIBED"));
+        for row in &rows {
+            assert_eq!(text(&row[2]).as_deref(), Some("a@example.com"));
+        }
+        let requests = host::requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0]
+            .url
+            .contains("/communication-preferences/v3/status/email/a%2Bb%40example.com"));
+    }
+
+    #[test]
+    fn backoff_delay_is_jittered_within_half_the_backoff() {
+        for attempt in 0..4 {
+            let backoff = RETRY_BASE_DELAY_MS << attempt;
+            let first = backoff_delay_ms(attempt, 1);
+            let second = backoff_delay_ms(attempt, 2);
+            assert_ne!(first, second);
+            for delay in [first, second] {
+                assert!((backoff / 2..=backoff).contains(&delay), "{} of {}", delay, backoff);
+            }
+        }
+    }
+
+    #[test]
+    fn epoch_millis_out_converts_iso_timestamps() {
+        let mut fdw = fdw();
+        host::respond(
+            200,
+            r#"{"results": [
+                {"id": "1", "createdAt": "2023-03-15T12:00:00.250Z"},
+                {"id": "2", "createdAt": "1678881600250"}
+            ]}"#,
+        );
+        let scan = scan(
+            &[("object", "contacts"), ("hs_value_kind", "createdAt:epoch_millis_out")],
+            &[("createdAt", TypeOid::I64)],
+        );
+        let rows = scan_rows(&mut fdw, &scan).unwrap();
+        assert_eq!(text(&rows[0][0]).as_deref(), Some("1678881600250"));
+        assert_eq!(text(&rows[1][0]).as_deref(), Some("1678881600250"));
+    }
+
+    #[test]
+    fn unknown_custom_object_ids_are_rejected_against_the_schemas() {
+        assert!(is_custom_object_type("2-3508482"));
+        assert!(is_custom_object_type("p1234_cars"));
+        assert!(!is_custom_object_type("contact"));
+
+        let schemas = ["cars".to_owned(), "2-123".to_owned()];
+        let opts = options(&[]);
+        assert!(ObjectSpec::new("2-123", &opts, Some(&schemas)).unwrap().crm);
+        let err = ObjectSpec::new("2-999", &opts, Some(&schemas)).unwrap_err();
+        assert!(err.starts_with("Unknown object type '2-999', valid types are: contacts"));
+        assert!(err.ends_with("cars, 2-123"));
+        // without the schema list, anything shaped like a custom id is tried
+        assert!(ObjectSpec::new("2-999", &opts, None).is_ok());
+    }
+
+    #[test]
+    fn object_schemas_are_fetched_once_per_server() {
+        host::respond(200, r#"{"results": [{"name": "cars", "objectTypeId": "2-123"}]}"#);
+        let server = [("validate_on_init", "true"), ("api_key", "schema-cache-key")];
+        let first = fdw_with(&server);
+        let second = fdw_with(&server);
+        assert_eq!(host::requests().len(), 1);
+        assert_eq!(first.object_schemas, second.object_schemas);
+        let schemas = ["cars".to_owned(), "2-123".to_owned()];
+        assert_eq!(second.object_schemas.as_deref(), Some(&schemas[..]));
+    }
+
+    #[test]
+    fn json_null_mode_applies_to_absent_and_null_properties() {
+        let body = r#"{"results": [{"id": "1", "properties": {"tags": null}}]}"#;
+        let columns = [("tags", TypeOid::Json), ("extra", TypeOid::Json)];
+        for (mode, expected) in [("sql_null", None), ("json_null", Some("null"))] {
+            let options = [("object", "contacts"), ("json_null_mode", mode)];
+            let rows = single_page(&options, &columns, body).unwrap();
+            assert_eq!(text(&rows[0][0]).as_deref(), expected, "{} null", mode);
+            assert_eq!(text(&rows[0][1]).as_deref(), expected, "{} absent", mode);
+        }
+    }
+
+    #[test]
+    fn lifecycle_history_exposes_the_stage_transitions() {
+        let mut fdw = fdw();
+        host::respond(
+            200,
+            r#"{"results": [{"id": "1", "properties": {"lifecyclestage": "customer"},
+                "propertiesWithHistory": {"lifecyclestage": [
+                    {"value": "customer", "timestamp": "2023-03-01T00:00:00Z"},
+                    {"value": "lead", "timestamp": "2023-01-01T00:00:00Z"}
+                ]}}]}"#,
+        );
+        let scan = scan(
+            &[("object", "contacts"), ("lifecycle_history", "true")],
+            &[("id", TypeOid::String), ("lifecyclestage_history", TypeOid::Json)],
+        );
+        let rows = scan_rows(&mut fdw, &scan).unwrap();
+        let history: JsonValue = serde_json::from_str(&text(&rows[0][1]).unwrap()).unwrap();
+        let stages: Vec<&str> = history
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["value"].as_str().unwrap())
+            .collect();
+        assert_eq!(stages, ["customer", "lead"]);
+        assert!(host::requests()[0].url.contains("propertiesWithHistory=lifecyclestage"));
+    }
+
+    #[test]
+    fn custom_reads_can_post_a_json_body() {
+        let table = [
+            ("object", "custom"),
+            ("endpoint", "/marketing/v3/reports/query"),
+            ("request_method", "post"),
+            ("request_body", r#"{"report": "visits"}"#),
+        ];
+        let mut fdw = fdw();
+        host::respond(
+            200,
+            r#"{"results": [{"id": "1"}], "paging": {"next": {"after": "abc"}}}"#,
+        );
+        host::respond(200, r#"{"results": [{"id": "2"}]}"#);
+        let rows = scan_rows(&mut fdw, &scan(&table, &[("id", TypeOid::String)])).unwrap();
+        assert_eq!(rows.len(), 2);
+
+        let requests = host::requests();
+        assert!(requests.iter().all(|req| matches!(req.method, http::Method::Post)));
+        assert!(requests[0].url.ends_with("/marketing/v3/reports/query"));
+        assert_eq!(requests[0].body, r#"{"report":"visits"}"#);
+        let body: JsonValue = serde_json::from_str(&requests[1].body).unwrap();
+        assert_eq!(body, serde_json::json!({ "report": "visits", "after": "abc" }));
+
+        for (request_body, error) in [
+            ("{report", "request_body is not valid JSON"),
+            ("[1]", "request_body must be a JSON object"),
+        ] {
+            let mut opts = options(&table);
+            opts.insert("request_body".to_owned(), request_body.to_owned());
+            let err = ObjectSpec::new("custom", &opts, None).unwrap_err();
+            assert!(err.starts_with(error), "{}", err);
+        }
+    }
+
+    #[test]
+    fn expired_access_tokens_without_refresh_get_guidance() {
+        let expired = host::response(
+            401,
+            r#"{"category": "EXPIRED_AUTHENTICATION", "message": "The OAuth token expired"}"#,
+        );
+        let err = status_error(&expired, false);
+        assert!(err.starts_with("HubSpot access token appears to have expired"), "{}", err);
+        assert!(err.contains("The OAuth token expired"));
+        assert!(!status_error(&expired, true).contains("appears to have expired"));
+
+        let invalid = host::response(
+            401,
+            r#"{"category": "INVALID_AUTHENTICATION", "message": "Authentication failed"}"#,
+        );
+        assert_eq!(
+            status_error(&invalid, false),
+            "HubSpot API request failed with status 401: Authentication failed"
+        );
+
+        let mut fdw = fdw_with(&[("access_token", "expired")]);
+        host::respond_with(expired);
+        let err = scan_rows(&mut fdw, &scan(&[("object", "contacts")], &[])).unwrap_err();
+        assert!(err.contains("refresh credentials"), "{}", err);
+    }
+
+    #[test]
+    fn id_only_requests_and_returns_only_the_id() {
+        let mut fdw = fdw();
+        host::respond(
+            200,
+            r#"{"results": [{"id": "1", "properties": {"hs_object_id": "1", "email": "a@b.c"}}]}"#,
+        );
+        let scan = scan(
+            &[("object", "contacts"), ("id_only", "true")],
+            &[("id", TypeOid::String), ("email", TypeOid::String)],
+        );
+        let rows = scan_rows(&mut fdw, &scan).unwrap();
+        assert_eq!(text(&rows[0][0]).as_deref(), Some("1"));
+        assert!(rows[0][1].is_none());
+        let url = &host::requests()[0].url;
+        assert!(url.contains("properties=hs_object_id"), "{}", url);
+        assert!(!url.contains("email"), "{}", url);
+    }
+
+    #[test]
+    fn map_shaped_results_get_the_id_filled_in() {
+        let spec = ObjectSpec {
+            results_path: "results".to_owned(),
+            results_shape: ResultsShape::Map,
+            ..ObjectSpec::default()
+        };
+        let resp_json = serde_json::json!({ "results": {
+            "101": { "properties": { "name": "a" } },
+            "102": { "id": "kept", "properties": { "name": "b" } },
+        }});
+        let records = extract_records(&spec, &resp_json).unwrap();
+        let ids: Vec<&str> = records.iter().map(|r| r["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, ["101", "kept"]);
+        assert_eq!(records[0]["properties"]["name"], "a");
+
+        let array = serde_json::json!({ "results": [{ "id": "1" }] });
+        let err = extract_records(&spec, &array).unwrap_err();
+        assert_eq!(err, "expected results at 'results' to be an object");
+    }
+
+    #[test]
+    fn forms_expose_their_field_groups_as_json() {
+        let rows = single_page(
+            &[("object", "forms")],
+            &[("id", TypeOid::String), ("name", TypeOid::String), ("fieldGroups", TypeOid::Json)],
+            r#"{"results": [{"id": "f1", "name": "Signup", "fieldGroups": [
+                {"groupType": "default_group", "fields": [{"name": "email", "required": true}]}
+            ]}]}"#,
+        )
+        .unwrap();
+        assert_eq!(text(&rows[0][1]).as_deref(), Some("Signup"));
+        let groups: JsonValue = serde_json::from_str(&text(&rows[0][2]).unwrap()).unwrap();
+        assert_eq!(groups[0]["fields"][0]["name"], "email");
+        assert!(host::requests()[0].url.ends_with("/marketing/v3/forms"));
+    }
+
+    #[test]
+    fn properties_too_long_for_the_list_url_go_to_search() {
+        // 97 properties fit if the query prefix and parameters are left out
+        for count in [97, 200] {
+            let names: Vec<String> = (0..count).map(|i| format!("p{:018}", i)).collect();
+            let columns: Vec<(&str, TypeOid)> =
+                names.iter().map(|name| (name.as_str(), TypeOid::String)).collect();
+            let mut fdw = fdw();
+            host::respond(200, r#"{"total": 0, "results": []}"#);
+            scan_rows(&mut fdw, &scan(&[("object", "contacts")], &columns)).unwrap();
+
+            let req = host::requests().pop().unwrap();
+            assert!(req.url.ends_with("/crm/v3/objects/contacts/search"), "{}", req.url);
+            let body: JsonValue = serde_json::from_str(&req.body).unwrap();
+            assert_eq!(body["properties"].as_array().unwrap().len(), count);
+            assert!(fdw.list_request().url.len() > MAX_URL_LENGTH);
+        }
+    }
+
+    #[test]
+    fn conversion_failures_are_reported_in_the_errors_column() {
+        let rows = single_page(
+            &[("object", "deals"), ("on_conversion_error", "null")],
+            &[
+                ("id", TypeOid::String),
+                ("amount", TypeOid::F64),
+                ("dealname", TypeOid::String),
+                ("_errors", TypeOid::Json),
+            ],
+            r#"{"results": [{"id": "1", "properties": {"amount": "lots", "dealname": "Big"}}]}"#,
+        )
+        .unwrap();
+        assert!(rows[0][1].is_none());
+        assert_eq!(text(&rows[0][2]).as_deref(), Some("Big"));
+        let errors: JsonValue = serde_json::from_str(&text(&rows[0][3]).unwrap()).unwrap();
+        let error = "cannot convert value \"lots\" to type TypeOid::F64";
+        assert_eq!(errors, serde_json::json!({ "amount": error }));
+        let warning = format!("warning: {} (column 'amount' of object 'deals')", error);
+        assert!(host::messages().contains(&warning));
+    }
+
+    #[test]
+    fn deals_expose_associated_contact_and_company_ids() {
+        let record = serde_json::json!({ "id": "1", "associations": {
+            "contacts": { "results": [{ "id": "11", "type": "deal_to_contact" }] },
+            "companies": { "results": [{ "id": "21", "type": "deal_to_company" }] },
+        }});
+        assert_eq!(
+            lookup_value(&record, "associations.contacts.results"),
+            Some(&serde_json::json!([{ "id": "11", "type": "deal_to_contact" }]))
+        );
+        assert_eq!(lookup_value(&record, "associations.tickets.results"), None);
+
+        let mut fdw = fdw();
+        host::respond(200, &serde_json::json!({ "results": [record] }).to_string());
+        let scan = scan(
+            &[("object", "deals"), ("associations", "contacts,companies")],
+            &[
+                ("associations.contacts.results", TypeOid::Json),
+                ("associations.companies.results", TypeOid::Json),
+            ],
+        );
+        let rows = scan_rows(&mut fdw, &scan).unwrap();
+        let contacts: JsonValue = serde_json::from_str(&text(&rows[0][0]).unwrap()).unwrap();
+        let companies: JsonValue = serde_json::from_str(&text(&rows[0][1]).unwrap()).unwrap();
+        assert_eq!(contacts[0]["id"], "11");
+        assert_eq!(companies[0]["id"], "21");
+        assert!(host::requests()[0].url.contains("associations=contacts,companies"));
+    }
+
+    #[test]
+    fn server_errors_abort_at_once_without_retry_on_5xx() {
+        let strict = fdw_with(&[("retry_on_5xx", "false")]);
+        assert!(!strict.is_retryable(503));
+        assert!(strict.is_retryable(429));
+        assert!(fdw().is_retryable(503));
+        assert!(!fdw().is_retryable(404));
+
+        let mut strict = strict;
+        host::respond(503, r#"{"message": "unavailable"}"#);
+        host::respond(200, r#"{"results": []}"#);
+        let err = scan_rows(&mut strict, &scan(&[("object", "contacts")], &[])).unwrap_err();
+        assert!(err.starts_with("HubSpot API request failed with status 503"), "{}", err);
+        assert_eq!(host::requests().len(), 1);
+    }
+
+    #[test]
+    fn fetched_at_is_the_page_fetch_time() {
+        let rows = single_page(
+            &[("object", "contacts")],
+            &[("id", TypeOid::String), ("_fetched_at", TypeOid::Timestamptz)],
+            r#"{"results": [{"id": "1"}, {"id": "2"}]}"#,
+        )
+        .unwrap();
+        for row in &rows {
+            assert!(matches!(row[1], Some(Cell::Timestamptz(1_700_000_000_000_000))));
+        }
+    }
+
+    #[test]
+    fn page_size_is_clamped_per_endpoint_with_a_warning() {
+        for (endpoint, cap) in [("list", 100), ("search", 200)] {
+            let mut fdw = fdw();
+            host::respond(200, r#"{"total": 0, "results": []}"#);
+            let scan = scan(
+                &[("object", "contacts"), ("page_size", "500"), ("force_endpoint", endpoint)],
+                &[("email", TypeOid::String)],
+            );
+            scan_rows(&mut fdw, &scan).unwrap();
+            assert_eq!(fdw.page_size, Some(cap));
+            let warning = format!(
+                "warning: page_size 500 exceeds the maximum of {} for this endpoint, using {}",
+                cap, cap
+            );
+            assert!(host::messages().contains(&warning), "{}", endpoint);
+        }
+        let req = host::requests();
+        assert!(req[0].url.contains("limit=100"));
+        assert_eq!(serde_json::from_str::<JsonValue>(&req[1].body).unwrap()["limit"], 200);
+
+        single_page(&[("object", "forms"), ("page_size", "10")], &[], r#"{"results": []}"#)
+            .unwrap();
+        let ignored = "warning: page_size is not supported for object 'forms' and is ignored";
+        assert!(host::messages().contains(&ignored.to_owned()));
+    }
+
+    // the search window a search request is restricted to, if any
+    fn request_window(req: &http::Request, property: &str) -> Option<(i64, i64)> {
+        let body: JsonValue = serde_json::from_str(&req.body).unwrap();
+        let filters = body["filterGroups"][0]["filters"].as_array()?;
+        let bound = |operator: &str| {
+            filters
+                .iter()
+                .find(|f| f["propertyName"] == property && f["operator"] == operator)
+                .and_then(|f| f["value"].as_str()?.parse().ok())
+        };
+        Some((bound("GTE")?, bound("LT")?))
+    }
+
+    #[test]
+    fn searches_over_the_result_limit_are_bisected() {
+        let mut fdw = fdw();
+        host::respond(200, r#"{"total": 15000, "results": [{"id": "0"}]}"#);
+        host::respond(
+            200,
+            r#"{"total": 7000, "results": [{"id": "1"}, {"id": "2"}],
+                "paging": {"next": {"after": "2"}}}"#,
+        );
+        host::respond(200, r#"{"total": 7000, "results": [{"id": "3"}]}"#);
+        host::respond(200, r#"{"total": 8000, "results": [{"id": "3"}, {"id": "4"}]}"#);
+        let scan = scan(
+            &[("object", "contacts"), ("force_endpoint", "search")],
+            &[("id", TypeOid::String)],
+        );
+        let rows = scan_rows(&mut fdw, &scan).unwrap();
+        let ids: Vec<_> = rows.iter().filter_map(|row| text(&row[0])).collect();
+        assert_eq!(ids, ["1", "2", "3", "4"]);
+
+        let requests = host::requests();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(request_window(&requests[0], "createdate"), None);
+        let (start, mid) = request_window(&requests[1], "createdate").unwrap();
+        assert_eq!(start, 0);
+        assert_eq!(request_window(&requests[2], "createdate"), Some((start, mid)));
+        let (second_start, end) = request_window(&requests[3], "createdate").unwrap();
+        assert_eq!(second_start, mid);
+        assert_eq!(mid, end / 2);
+        assert_eq!(fdw.search_total, Some(15000));
+    }
+
+    #[test]
+    fn small_searches_are_not_windowed() {
+        let mut fdw = fdw();
+        host::respond(200, r#"{"total": 5, "results": [{"id": "1"}]}"#);
+        let search = scan(&[("object", "calls"), ("force_endpoint", "search")], &[]);
+        scan_rows(&mut fdw, &search).unwrap();
+        let body: JsonValue = serde_json::from_str(&host::requests()[0].body).unwrap();
+        assert!(body.get("filterGroups").is_none(), "{}", body);
+
+        // engagements and custom objects partition on hs_createdate
+        assert_eq!(fdw.partition_property, "hs_createdate");
+        let scan = scan(
+            &[("object", "calls"), ("partition_property", "createdate")],
+            &[],
+        );
+        let err = fdw.start_scan(&scan).unwrap_err();
+        assert_eq!(
+            err,
+            "partition_property must be 'hs_createdate' or 'hs_lastmodifieddate' for \
+             object 'calls', got 'createdate'"
+        );
+    }
+
+    #[test]
+    fn keyset_is_logged_at_the_end_of_the_scan() {
+        single_page(
+            &[("object", "contacts"), ("emit_keyset", "true")],
+            &[("id", TypeOid::String)],
+            r#"{"results": [
+                {"id": "1", "updatedAt": "2023-03-01T00:00:00Z"},
+                {"id": "2", "updatedAt": "2023-03-02T00:00:00Z"}
+            ]}"#,
+        )
+        .unwrap();
+        let messages = host::messages();
+        let keyset = messages.iter().find_map(|m| m.strip_prefix("info: keyset: ")).unwrap();
+        assert_eq!(
+            serde_json::from_str::<JsonValue>(keyset).unwrap(),
+            serde_json::json!({
+                "object": "contacts",
+                "after": null,
+                "search_window": null,
+                "last_id": "2",
+                "last_updated_at": "2023-03-02T00:00:00Z",
+                "rows": 2,
+                "complete": true,
+            })
+        );
+    }
+
+    #[test]
+    fn multi_value_columns_split_secondary_emails() {
+        let rows = single_page(
+            &[("object", "contacts"), ("multi_value_columns", "hs_additional_emails")],
+            &[("hs_additional_emails", TypeOid::Json)],
+            r#"{"results": [
+                {"id": "1", "properties": {"hs_additional_emails": "a@example.com;b@example.com"}},
+                {"id": "2", "properties": {"hs_additional_emails": null}},
+                {"id": "3", "properties": {"hs_additional_emails": ""}}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(text(&rows[0][0]).as_deref(), Some(r#"["a@example.com","b@example.com"]"#));
+        assert_eq!(text(&rows[1][0]).as_deref(), Some("[]"));
+        assert_eq!(text(&rows[2][0]).as_deref(), Some("[]"));
+    }
+
+    #[test]
+    fn each_page_carries_its_own_request_id() {
+        let mut fdw = fdw();
+        host::respond(200, r#"{"results": [{"id": "1"}], "paging": {"next": {"after": "1"}}}"#);
+        host::respond(500, r#"{"message": "try again"}"#);
+        host::respond(200, r#"{"results": [{"id": "2"}]}"#);
+        let rows = scan_rows(&mut fdw, &scan(&[("object", "contacts")], &[])).unwrap();
+        assert_eq!(rows.len(), 2);
+
+        let ids: Vec<String> = host::requests()
+            .iter()
+            .map(|req| {
+                let header = req.headers.iter().find(|(name, _)| name == "x-request-id");
+                header.unwrap().1.clone()
+            })
+            .collect();
+        assert_eq!(ids.len(), 3);
+        assert_ne!(ids[0], ids[1]);
+        // the retry of the second page keeps its id
+        assert_eq!(ids[1], ids[2]);
+        assert!(ids[0].starts_with("hubspot-fdw-1700000000-contacts-1-"), "{}", ids[0]);
+    }
+
+    #[test]
+    fn refreshed_tokens_are_persisted_per_server() {
+        let server = [
+            ("refresh_token", "refresh"),
+            ("client_id", "client"),
+            ("client_secret", "secret"),
+            ("persist_refreshed_token", "true"),
+        ];
+        let mut fdw = fdw_with(&server);
+        host::respond(200, r#"{"access_token": "fresh", "expires_in": 1800}"#);
+        host::respond(200, r#"{"results": []}"#);
+        scan_rows(&mut fdw, &scan(&[("object", "contacts")], &[])).unwrap();
+
+        let requests = host::requests();
+        assert!(requests[0].url.ends_with("/oauth/v1/token"));
+        let auth = requests[1].headers.iter().find(|(name, _)| name == "authorization");
+        assert_eq!(auth.unwrap().1, "Bearer fresh");
+        assert_eq!(stats::get_metadata(FDW_NAME), None);
+        let metadata = stats::get_metadata(&fdw.token_metadata_key()).unwrap();
+        assert_eq!(
+            serde_json::from_str::<JsonValue>(&metadata).unwrap(),
+            serde_json::json!({
+                "api_url": "https://api.hubapi.com",
+                "client_id": "client",
+                "access_token": "fresh",
+                "expires_at": 1_700_000_000 + 1800,
+            })
+        );
+
+        // another worker of the same server reuses it, other servers don't
+        assert_eq!(fdw_with(&server).api_key, "fresh");
+        let mut other = server.to_vec();
+        other[1] = ("client_id", "other-client");
+        assert_eq!(fdw_with(&other).api_key, "");
+        other[1] = ("client_id", "client");
+        other.push(("api_url", "https://eu1.api.hubapi.com"));
+        assert_eq!(fdw_with(&other).api_key, "");
+
+        // nor is a token written for another client under the same key
+        let foreign = serde_json::json!({
+            "api_url": "https://api.hubapi.com",
+            "client_id": "other-client",
+            "access_token": "foreign",
+            "expires_at": 1_700_000_000 + 1800,
+        });
+        stats::set_metadata(&fdw.token_metadata_key(), &Some(foreign.to_string()));
+        assert_eq!(fdw_with(&server).api_key, "");
+    }
+
+    #[test]
+    fn total_and_has_more_come_from_the_page() {
+        let mut fdw = fdw();
+        host::respond(
+            200,
+            r#"{"total": 2, "results": [{"id": "1"}], "paging": {"next": {"after": "1"}}}"#,
+        );
+        host::respond(200, r#"{"total": 2, "results": [{"id": "2"}]}"#);
+        let columns = [("_total", TypeOid::I64), ("_has_more", TypeOid::Bool)];
+        let search = scan(&[("object", "contacts"), ("force_endpoint", "search")], &columns);
+        let rows = scan_rows(&mut fdw, &search).unwrap();
+        let values: Vec<_> = rows.iter().map(|row| (text(&row[0]), text(&row[1]))).collect();
+        assert_eq!(
+            values,
+            [
+                (Some("2".to_owned()), Some("true".to_owned())),
+                (Some("2".to_owned()), Some("false".to_owned())),
+            ]
+        );
+
+        let rows = single_page(&[("object", "contacts")], &columns, r#"{"results": [{"id": "1"}]}"#)
+            .unwrap();
+        assert!(rows[0][0].is_none());
+        assert_eq!(text(&rows[0][1]).as_deref(), Some("false"));
+    }
+
+    #[test]
+    fn source_enum_values_map_to_their_labels() {
+        let mut fdw = fdw();
+        host::respond(
+            200,
+            r#"{"results": [{"name": "hs_object_source", "options": [
+                {"value": "CRM_UI", "label": "CRM UI"},
+                {"value": "FORM", "label": "Form"}
+            ]}]}"#,
+        );
+        host::respond(
+            200,
+            r#"{"results": [
+                {"id": "1", "properties": {"hs_object_source": "CRM_UI", "email": "CRM_UI"}},
+                {"id": "2", "properties": {"hs_object_source": "IMPORT", "email": null}}
+            ]}"#,
+        );
+        let scan = scan(
+            &[("object", "contacts"), ("enum_labels", "true")],
+            &[("hs_object_source", TypeOid::String), ("email", TypeOid::String)],
+        );
+        let rows = scan_rows(&mut fdw, &scan).unwrap();
+        assert_eq!(text(&rows[0][0]).as_deref(), Some("CRM UI"));
+        // other properties and values without a label are left as they are
+        assert_eq!(text(&rows[0][1]).as_deref(), Some("CRM_UI"));
+        assert_eq!(text(&rows[1][0]).as_deref(), Some("IMPORT"));
+        assert!(host::requests()[0].url.ends_with("/crm/v3/properties/contacts"));
+    }
+
+    #[test]
+    fn all_null_selected_properties_fall_back_to_search() {
+        let table = [("object", "contacts"), ("auto_search_fallback", "true")];
+        let mut fdw = fdw();
+        host::respond(200, r#"{"results": [{"id": "1", "properties": {"email": null}}]}"#);
+        host::respond(
+            200,
+            r#"{"total": 1, "results": [{"id": "1", "properties": {"email": "a@b.c"}}]}"#,
+        );
+        let rows = scan_rows(&mut fdw, &scan(&table, &[("email", TypeOid::String)])).unwrap();
+        assert_eq!(text(&rows[0][0]).as_deref(), Some("a@b.c"));
+        let requests = host::requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].url.ends_with("/crm/v3/objects/contacts/search"));
+        assert!(host::messages().contains(
+            &"info: Property 'email' is null on the first page from the list endpoint, \
+              retrying with the search endpoint"
+                .to_owned()
+        ));
+
+        // the default properties, such as firstName which comes back as
+        // firstname, don't count
+        let rows = single_page(
+            &table,
+            &[("id", TypeOid::String), ("createdAt", TypeOid::String)],
+            r#"{"results": [{"id": "2", "createdAt": "2023-01-01T00:00:00Z",
+                "properties": {"firstname": "Ann", "email": null}}]}"#,
+        )
+        .unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(host::requests().len(), 3);
+    }
+
+    #[test]
+    fn scan_deadline_trips_mid_scan() {
+        let mut fdw = fdw_with(&[("scan_deadline_ms", "5000")]);
+        host::respond(200, r#"{"results": [{"id": "1"}], "paging": {"next": {"after": "1"}}}"#);
+        host::respond(200, r#"{"results": [{"id": "2"}]}"#);
+        let scan = scan(&[("object", "contacts")], &[("id", TypeOid::String)]);
+        fdw.start_scan(&scan).unwrap();
+        assert!(fdw.next_row(&scan).unwrap().is_some());
+
+        host::advance(6000);
+        let err = fdw.next_row(&scan).unwrap_err();
+        assert_eq!(err, "scan deadline of 5000 ms exceeded after fetching 1 pages");
+        assert_eq!(host::requests().len(), 1);
+    }
+
+    #[test]
+    fn records_without_properties_read_as_null() {
+        let rows = single_page(
+            &[("object", "contacts")],
+            &[("id", TypeOid::String), ("email", TypeOid::String), ("tags", TypeOid::Json)],
+            r#"{"results": [{"id": "1"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(text(&rows[0][0]).as_deref(), Some("1"));
+        assert!(rows[0][1].is_none());
+        assert!(rows[0][2].is_none());
+
+        // records of other endpoints never have `properties`, so a misspelled
+        // column is still an error there
+        let err = single_page(
+            &[("object", "forms")],
+            &[("nmae", TypeOid::String)],
+            r#"{"results": [{"id": "f1", "name": "Signup"}]}"#,
+        );
+        assert_eq!(err.unwrap_err(), "object 'forms': source column 'nmae' not found");
+    }
+
+    #[test]
+    fn conversion_errors_name_the_aliased_table() {
+        let table = [("object", "deals"), ("object_alias", "pipeline")];
+        let err = single_page(
+            &table,
+            &[("dealname", TypeOid::String)],
+            r#"{"results": [{"id": "1", "properties": {}}]}"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "table 'pipeline' (object 'deals'): source column 'dealname' not found"
+        );
+
+        let err = single_page(
+            &table,
+            &[("amount", TypeOid::I64)],
+            r#"{"results": [{"id": "1", "properties": {"amount": "lots"}}]}"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "table 'pipeline' (object 'deals'): cannot convert column 'amount' to type \
+             TypeOid::I64"
+        );
+    }
+
+    #[test]
+    fn preserve_number_text_keeps_numbers_as_sent() {
+        let rows = single_page(
+            &[("object", "custom"), ("endpoint", "/orders"), ("preserve_number_text", "true")],
+            &[("amount", TypeOid::String), ("order_id", TypeOid::String), ("line", TypeOid::Json)],
+            r#"{"results": [{"amount": 1500.00, "order_id": 12345678901234567890123,
+                "line": {"price": 19.90}}]}"#,
+        )
+        .unwrap();
+        assert_eq!(text(&rows[0][0]).as_deref(), Some("1500.00"));
+        assert_eq!(text(&rows[0][1]).as_deref(), Some("12345678901234567890123"));
+        assert_eq!(text(&rows[0][2]).as_deref(), Some(r#"{"price":19.90}"#));
+    }
+
+    #[test]
+    fn property_history_is_grouped_into_revisions_newest_first() {
+        let record = serde_json::json!({ "id": "7", "propertiesWithHistory": {
+            "email": [
+                { "value": "new@b.c", "timestamp": "2023-02-01T00:00:00Z",
+                  "sourceType": "CRM_UI", "sourceId": "u1" },
+                { "value": "old@b.c", "timestamp": "2023-01-01T00:00:00Z",
+                  "sourceType": "IMPORT", "sourceId": null },
+            ],
+            "phone": [
+                { "value": "555", "timestamp": "2023-02-01T00:00:00Z",
+                  "sourceType": "CRM_UI", "sourceId": "u1" },
+            ],
+        }});
+        assert_eq!(
+            property_revisions(&record),
+            [
+                serde_json::json!({
+                    "id": "7",
+                    "timestamp": "2023-02-01T00:00:00Z",
+                    "sourceType": "CRM_UI",
+                    "sourceId": "u1",
+                    "changes": { "email": "new@b.c", "phone": "555" },
+                }),
+                serde_json::json!({
+                    "id": "7",
+                    "timestamp": "2023-01-01T00:00:00Z",
+                    "sourceType": "IMPORT",
+                    "sourceId": null,
+                    "changes": { "email": "old@b.c" },
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn interleaved_scans_share_the_rate_limit() {
+        // two instances, as two backends would be, scan four pages each,
+        // taking turns. Their requests are served in the order they're sent
+        let next = r#"{"results": [{"id": "1"}], "paging": {"next": {"after": "1"}}}"#;
+        for _ in 0..6 {
+            host::respond(200, next);
+        }
+        host::respond(200, r#"{"results": [{"id": "1"}]}"#);
+        host::respond(200, r#"{"results": [{"id": "1"}]}"#);
+        let mut contacts = fdw_with(&[("rate_limit_per_sec", "3")]);
+        let mut companies = fdw_with(&[("rate_limit_per_sec", "3")]);
+        let contacts_scan = scan(&[("object", "contacts")], &[("id", TypeOid::String)]);
+        let companies_scan = scan(&[("object", "companies")], &[("id", TypeOid::String)]);
+        contacts.start_scan(&contacts_scan).unwrap();
+        companies.start_scan(&companies_scan).unwrap();
+        let mut rows = 0;
+        loop {
+            let contact = contacts.next_row(&contacts_scan).unwrap();
+            let company = companies.next_row(&companies_scan).unwrap();
+            if contact.is_none() && company.is_none() {
+                break;
+            }
+            rows += usize::from(contact.is_some()) + usize::from(company.is_some());
+        }
+        assert_eq!(rows, 8);
+
+        // with its own bucket, each scan would send three a second
+        let sent: Vec<(i64, String)> = host::request_times()
+            .into_iter()
+            .zip(host::requests())
+            .map(|(secs, req)| (secs, req.url))
+            .collect();
+        assert_eq!(sent.len(), 8);
+        let object = |url: &str| {
+            let path = url.split('?').next().unwrap();
+            path.rsplit('/').next().unwrap().to_owned()
+        };
+        let per_second: Vec<Vec<String>> = sent
+            .chunk_by(|a, b| a.0 == b.0)
+            .map(|second| second.iter().map(|(_, url)| object(url)).collect())
+            .collect();
+        assert_eq!(
+            per_second,
+            [
+                vec!["contacts", "companies", "contacts"],
+                vec!["companies", "contacts", "companies"],
+                vec!["contacts", "companies"],
+            ]
+        );
+    }
+
+    #[test]
+    fn date_properties_read_as_their_day() {
+        let rows = single_page(
+            &[("object", "deals")],
+            &[("closedate", TypeOid::Date)],
+            r#"{"results": [
+                {"id": "1", "properties": {"closedate": "2023-03-15"}},
+                {"id": "2", "properties": {"closedate": "2023-03-15T00:00:00Z"}},
+                {"id": "3", "properties": {"closedate": "2023-03-15T23:59:59.999Z"}}
+            ]}"#,
+        )
+        .unwrap();
+        // 2023-03-15 in seconds since the epoch
+        for row in &rows {
+            assert!(matches!(row[0], Some(Cell::Date(1_678_838_400))), "{:?}", row[0]);
+        }
+    }
+
+    #[test]
+    fn default_properties_warning_fires_without_property_columns() {
+        single_page(
+            &[("object", "contacts"), ("warn_on_default_properties", "true")],
+            &[("id", TypeOid::String), ("createdAt", TypeOid::String)],
+            r#"{"results": []}"#,
+        )
+        .unwrap();
+        let warning = "warning: none of the selected columns of object 'contacts' is a HubSpot \
+                       property, so default properties firstName, lastName, email, phone are \
+                       fetched; set id_only 'true' to fetch only ids";
+        assert!(host::messages().contains(&warning.to_owned()), "{:?}", host::messages());
+    }
+
+    #[test]
+    fn status_522_is_retried_only_when_listed() {
+        let contacts = scan(&[("object", "contacts")], &[]);
+        host::respond(522, "origin timed out");
+        let err = scan_rows(&mut fdw(), &contacts).unwrap_err();
+        assert!(err.starts_with("HubSpot API request failed with status 522"), "{}", err);
+        assert_eq!(host::requests().len(), 1);
+
+        let mut fdw = fdw_with(&[("http_retry_status_codes", "429, 522")]);
+        assert_eq!(fdw.retry_status_codes, [429, 522]);
+        host::respond(522, "origin timed out");
+        host::respond(200, r#"{"results": [{"id": "1"}]}"#);
+        assert_eq!(scan_rows(&mut fdw, &contacts).unwrap().len(), 1);
+        assert_eq!(host::requests().len(), 3);
+    }
+
+    #[test]
+    fn small_integers_read_from_numbers_and_quoted_text() {
+        assert_eq!(json_integer(&serde_json::json!(42)), Some(42));
+        assert_eq!(json_integer(&serde_json::json!(" 42 ")), Some(42));
+        assert_eq!(json_integer(&serde_json::json!("4.2")), None);
+
+        let body = r#"{"results": [{"id": "1", "properties": {"num": "120"}},
+            {"id": "2", "properties": {"num": 7}},
+            {"id": "3", "properties": {"num": "70000"}}]}"#;
+        let columns = [("num", TypeOid::I16), ("_errors", TypeOid::String)];
+        let rows =
+            single_page(&[("object", "contacts"), ("on_conversion_error", "null")], &columns, body)
+                .unwrap();
+        assert!(matches!(rows[0][0], Some(Cell::I16(120))));
+        assert!(matches!(rows[1][0], Some(Cell::I16(7))));
+        assert!(rows[2][0].is_none());
+        assert_eq!(
+            text(&rows[2][1]).as_deref(),
+            Some("num: value \"70000\" is out of range for type TypeOid::I16")
+        );
+
+        let err = single_page(&[("object", "contacts")], &columns, body).unwrap_err();
+        let out_of_range = "cannot convert column 'num': value \"70000\" is out of range for \
+                            type TypeOid::I16";
+        assert!(err.ends_with(out_of_range), "{}", err);
+    }
+
+    #[test]
+    fn owner_id_filters_the_search() {
+        let mut fdw = fdw();
+        host::respond(200, r#"{"total": 1, "results": [{"id": "1"}]}"#);
+        scan_rows(&mut fdw, &scan(&[("object", "deals"), ("owner_id", "42")], &[])).unwrap();
+        let req = &host::requests()[0];
+        assert!(req.url.ends_with("/crm/v3/objects/deals/search"));
+        let body: JsonValue = serde_json::from_str(&req.body).unwrap();
+        assert_eq!(
+            body["filterGroups"],
+            serde_json::json!([{ "filters": [
+                { "propertyName": "hubspot_owner_id", "operator": "EQ", "value": "42" },
+            ]}])
+        );
+    }
+
+    #[test]
+    fn html_responses_report_their_content_type() {
+        let page = format!("<html><body>{}</body></html>", "Bad gateway ".repeat(30));
+        let resp = http::Response {
+            headers: vec![("Content-Type".to_owned(), "text/html; charset=utf-8".to_owned())],
+            ..host::response(200, &page)
+        };
+        let err = parse_json_body(&resp).unwrap_err();
+        let expected = format!(
+            "expected a JSON response but got content type 'text/html; charset=utf-8' with \
+             status 200: {}",
+            &page[..200]
+        );
+        assert_eq!(err, expected);
+    }
+
+    #[test]
+    fn op_tells_inserts_from_updates_since_the_watermark() {
+        let rows = single_page(
+            &[("object", "contacts"), ("cdc_watermark", "2023-03-01T00:00:00Z")],
+            &[("id", TypeOid::String), ("_op", TypeOid::String)],
+            r#"{"results": [
+                {"id": "1", "createdAt": "2023-03-02T00:00:00Z",
+                 "updatedAt": "2023-03-03T00:00:00Z"},
+                {"id": "2", "createdAt": "2023-01-01T00:00:00Z",
+                 "updatedAt": "2023-03-02T00:00:00Z"},
+                {"id": "3", "createdAt": "2023-01-01T00:00:00Z",
+                 "updatedAt": "2023-02-01T00:00:00Z"}
+            ]}"#,
+        )
+        .unwrap();
+        let ops: Vec<_> = rows.iter().map(|row| text(&row[1])).collect();
+        assert_eq!(ops, [Some("insert".to_owned()), Some("update".to_owned()), None]);
+    }
+
+    #[test]
+    fn history_is_truncated_to_the_most_recent_entries() {
+        let history: Vec<JsonValue> = (0..5)
+            .rev()
+            .map(|day| serde_json::json!({
+                "value": format!("stage{}", day),
+                "timestamp": format!("2023-01-0{}T00:00:00Z", day + 1),
+            }))
+            .collect();
+        let body = serde_json::json!({ "results": [{
+            "id": "1",
+            "propertiesWithHistory": { "dealstage": history },
+        }]});
+        let rows = single_page(
+            &[
+                ("object", "deals"),
+                ("properties_with_history", "dealstage"),
+                ("history_limit", "2"),
+            ],
+            &[("dealstage_history", TypeOid::Json)],
+            &body.to_string(),
+        )
+        .unwrap();
+        let kept: JsonValue = serde_json::from_str(&text(&rows[0][0]).unwrap()).unwrap();
+        let values: Vec<&str> =
+            kept.as_array().unwrap().iter().map(|v| v["value"].as_str().unwrap()).collect();
+        assert_eq!(values, ["stage4", "stage3"]);
+    }
+
+    #[test]
+    fn pagination_limit_errors_continue_through_list() {
+        let mut fdw = fdw();
+        host::respond(
+            200,
+            r#"{"total": 3, "results": [{"id": "1"}, {"id": "2"}],
+                "paging": {"next": {"after": "2"}}}"#,
+        );
+        host::respond(
+            400,
+            r#"{"category": "VALIDATION_ERROR", "subCategory": "PAGINATION_LIMIT",
+                "message": "too deep"}"#,
+        );
+        host::respond(200, r#"{"results": [{"id": "1"}, {"id": "2"}, {"id": "3"}]}"#);
+        let search = scan(
+            &[("object", "contacts"), ("force_endpoint", "search")],
+            &[("id", TypeOid::String)],
+        );
+        let rows = scan_rows(&mut fdw, &search).unwrap();
+        let ids: Vec<_> = rows.iter().filter_map(|row| text(&row[0])).collect();
+        assert_eq!(ids, ["1", "2", "3"]);
+
+        let requests = host::requests();
+        assert!(requests[1].url.ends_with("/search"));
+        assert!(requests[2].url.contains("/crm/v3/objects/contacts?limit=100"));
+        let fallback = "info: Search hit HubSpot's pagination limit, continuing with the list \
+                        endpoint";
+        assert!(host::messages().contains(&fallback.to_owned()));
+    }
+
+    #[test]
+    fn bool_text_format_formats_boolean_text_columns() {
+        let body = r#"{"results": [
+            {"id": "1", "properties": {"hs_is_closed": "true"}},
+            {"id": "2", "properties": {"hs_is_closed": "false"}},
+            {"id": "3", "properties": {"hs_is_closed": true}},
+            {"id": "4", "properties": {"hs_is_closed": false}}
+        ]}"#;
+        for (format, yes, no) in
+            [("yes_no", "Yes", "No"), ("true_false", "true", "false"), ("1_0", "1", "0")]
+        {
+            let rows = single_page(
+                &[("object", "deals"), ("bool_text_format", &format!("hs_is_closed:{}", format))],
+                &[("hs_is_closed", TypeOid::String)],
+                body,
+            )
+            .unwrap();
+            let values: Vec<_> = rows.iter().map(|row| text(&row[0]).unwrap()).collect();
+            assert_eq!(values, [yes, no, yes, no], "{}", format);
+        }
+
+        let err = single_page(
+            &[("object", "deals"), ("bool_text_format", "hs_is_closed:on_off")],
+            &[("hs_is_closed", TypeOid::String)],
+            body,
+        );
+        assert_eq!(err.unwrap_err(), "unsupported bool text format 'on_off'");
+    }
+
+    #[test]
+    fn tables_over_two_objects_tag_rows_with_their_type() {
+        let mut fdw = fdw();
+        host::respond(
+            200,
+            r#"{"results": [{"id": "1", "properties": {"hs_call_title": "Intro"}}]}"#,
+        );
+        host::respond(
+            200,
+            r#"{"results": [
+                {"id": "2", "properties": {"hs_email_subject": "Hello"}},
+                {"id": "3", "properties": {"hs_email_subject": "Re: Hello"}}
+            ]}"#,
+        );
+        let scan = scan(
+            &[("object", "calls, emails")],
+            &[
+                ("id", TypeOid::String),
+                ("_object_type", TypeOid::String),
+                ("hs_call_title", TypeOid::String),
+                ("hs_email_subject", TypeOid::String),
+            ],
+        );
+        let rows = scan_rows(&mut fdw, &scan).unwrap();
+        let values: Vec<Vec<_>> =
+            rows.iter().map(|row| row.iter().map(text).collect()).collect();
+        let row = |cells: [Option<&str>; 4]| cells.map(|cell| cell.map(str::to_owned)).to_vec();
+        assert_eq!(
+            values,
+            [
+                row([Some("1"), Some("calls"), Some("Intro"), None]),
+                row([Some("2"), Some("emails"), None, Some("Hello")]),
+                row([Some("3"), Some("emails"), None, Some("Re: Hello")]),
+            ]
+        );
+        let requests = host::requests();
+        assert!(requests[0].url.contains("/crm/v3/objects/calls?"));
+        assert!(requests[1].url.contains("/crm/v3/objects/emails?"));
+    }
+
+    #[test]
+    fn phone_numbers_normalize_to_e164() {
+        assert_eq!(phone_e164("(555) 123-4567").as_deref(), Some("+15551234567"));
+        assert_eq!(phone_e164("1-555-123-4567").as_deref(), Some("+15551234567"));
+        assert_eq!(phone_e164("+44 20 7946 0958").as_deref(), Some("+442079460958"));
+        // too short to tell, an extension, or a local number with a trunk 0
+        assert_eq!(phone_e164("123-4567"), None);
+        assert_eq!(phone_e164("555-123-4567 ext. 8"), None);
+        assert_eq!(phone_e164("020 7946 0958"), None);
+    }
+
+    #[test]
+    fn modified_within_minutes_filters_on_the_objects_modified_date() {
+        let objects = [("contacts", "lastmodifieddate"), ("deals", "hs_lastmodifieddate")];
+        for (object, property) in objects {
+            let mut fdw = fdw();
+            host::respond(200, r#"{"total": 0, "results": []}"#);
+            let scan = scan(&[("object", object), ("modified_within_minutes", "30")], &[]);
+            scan_rows(&mut fdw, &scan).unwrap();
+            let body: JsonValue =
+                serde_json::from_str(&host::requests().pop().unwrap().body).unwrap();
+            assert_eq!(
+                body["filterGroups"][0]["filters"],
+                serde_json::json!([{
+                    "propertyName": property,
+                    "operator": "GTE",
+                    "value": ((1_700_000_000 - 30 * 60) * 1000_i64).to_string(),
+                }])
+            );
+        }
+    }
+
+    #[test]
+    fn unnested_associations_give_a_row_per_associated_record() {
+        let mut fdw = fdw();
+        host::respond(
+            200,
+            r#"{"results": [{"id": "9", "properties": {"dealname": "Big"}, "associations": {
+                "contacts": {"results": [
+                    {"id": "11", "type": "deal_to_contact"},
+                    {"id": "12", "type": "deal_to_contact"},
+                    {"id": "11", "type": "deal_to_contact_primary"}
+                ]}}}]}"#,
+        );
+        host::respond(
+            200,
+            r#"{"results": [
+                {"id": "12", "properties": {"email": "b@example.com"}},
+                {"id": "11", "properties": {"email": "a@example.com"}}
+            ]}"#,
+        );
+        let scan = scan(
+            &[
+                ("object", "deals"),
+                ("unnest_associations", "contacts"),
+                ("association_properties", "email"),
+            ],
+            &[
+                ("id", TypeOid::String),
+                ("dealname", TypeOid::String),
+                ("associated.id", TypeOid::String),
+                ("associated.properties.email", TypeOid::String),
+            ],
+        );
+        let rows = scan_rows(&mut fdw, &scan).unwrap();
+        let rows: Vec<Vec<_>> =
+            rows.iter().map(|row| row.iter().map(|cell| text(cell).unwrap()).collect()).collect();
+        assert_eq!(
+            rows,
+            [
+                ["9", "Big", "11", "a@example.com"],
+                ["9", "Big", "12", "b@example.com"],
+            ]
+        );
+
+        let requests = host::requests();
+        assert!(requests[0].url.contains("associations=contacts"));
+        assert!(requests[1].url.ends_with("/crm/v3/objects/contacts/batch/read"));
+        let body: JsonValue = serde_json::from_str(&requests[1].body).unwrap();
+        let inputs = serde_json::json!([{ "id": "11" }, { "id": "12" }]);
+        assert_eq!(body, serde_json::json!({ "inputs": inputs, "properties": ["email"] }));
+    }
+
+    #[test]
+    fn schema_drift_warns_when_a_later_page_drops_a_property() {
+        let mut fdw = fdw();
+        host::respond(
+            200,
+            r#"{"results": [{"id": "1", "properties": {"email": "a@b.c", "phone": "555"}}],
+                "paging": {"next": {"after": "1"}}}"#,
+        );
+        host::respond(200, r#"{"results": [{"id": "2", "properties": {"email": "d@e.f"}}]}"#);
+        let scan = scan(
+            &[("object", "contacts"), ("schema_drift", "warn")],
+            &[("email", TypeOid::String), ("phone", TypeOid::Json)],
+        );
+        assert_eq!(scan_rows(&mut fdw, &scan).unwrap().len(), 2);
+        let warning = "warning: page 2 of object 'contacts' differs from the first page, \
+                       properties added: [], dropped: [phone]";
+        assert!(host::messages().contains(&warning.to_owned()), "{:?}", host::messages());
+    }
+
+    #[test]
+    fn endpoint_templates_render_object_and_option_placeholders() {
+        let opts = options(&[("list_id", "12/3"), ("portal", "eu")]);
+        assert_eq!(
+            render_endpoint_template("/crm/v3/lists/{list_id}/{object}", "contacts", &opts),
+            Ok("/crm/v3/lists/12%2F3/contacts".to_owned())
+        );
+        assert_eq!(
+            render_endpoint_template("/x/{missing}", "contacts", &opts),
+            Err("endpoint_template placeholder '{missing}' has no matching option".to_owned())
+        );
+        assert_eq!(
+            render_endpoint_template("/x/{portal", "contacts", &opts),
+            Err("unclosed placeholder in endpoint_template '/x/{portal'".to_owned())
+        );
+        assert_eq!(
+            render_endpoint_template("{portal}/x", "contacts", &opts),
+            Err("endpoint_template '{portal}/x' must start with '/'".to_owned())
+        );
+    }
+
+    #[test]
+    fn aliased_columns_project_one_property() {
+        let columns = ["email", "properties.email", "id", "amount_history"]
+            .map(str::to_owned)
+            .to_vec();
+        assert_eq!(
+            projected_properties(&columns, &["amount".to_owned()]),
+            vec!["email".to_owned()]
+        );
+    }
+
+    #[test]
+    fn merges_post_both_record_ids() {
+        let mut fdw = fdw();
+        fdw.start_modify(&options(&[("object", "companies"), ("write_operations", "merge")]))
+            .unwrap();
+        host::respond(200, "{}");
+        let cells = [("_merge_into".to_owned(), Some(Cell::I64(1001)))];
+        fdw.update_row(&Cell::String("2002".to_owned()), &cells).unwrap();
+
+        let req = host::requests().pop().unwrap();
+        assert!(matches!(req.method, http::Method::Post));
+        assert!(req.url.ends_with("/crm/v3/objects/companies/merge"));
+        let body: JsonValue = serde_json::from_str(&req.body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"primaryObjectId": "1001", "objectIdToMerge": "2002"})
+        );
+
+        let no_merge = [("name".to_owned(), Some(Cell::String("x".to_owned())))];
+        assert_eq!(
+            fdw.update_row(&Cell::I64(1), &no_merge),
+            Err("only merges are supported, by setting _merge_into".to_owned())
+        );
+    }
+
+    #[test]
+    fn request_plan_redacts_the_token() {
+        let mut fdw = fdw_with(&[("api_key", "secret-token"), ("debug_plan", "true")]);
+        host::respond(200, r#"{"results": []}"#);
+        let contacts = scan(&[("object", "contacts")], &[("id", TypeOid::String)]);
+        scan_rows(&mut fdw, &contacts).unwrap();
+
+        let messages = host::messages();
+        let plan = messages
+            .iter()
+            .find_map(|m| m.strip_prefix("info: request plan: "))
+            .expect("request plan logged");
+        let plan: JsonValue = serde_json::from_str(plan).unwrap();
+        assert_eq!(plan["object"], "contacts");
+        assert_eq!(plan["method"], "GET");
+        assert_eq!(plan["headers"]["authorization"], "Bearer [redacted]");
+        assert!(messages.iter().all(|m| !m.contains("secret-token")));
+    }
+
+    #[test]
+    fn two_empty_pages_claiming_more_end_the_scan() {
+        let empty = r#"{"results": [], "paging": {"next": {"after": "x"}}}"#;
+        host::respond(200, empty);
+        host::respond(200, empty);
+        let contacts = scan(&[("object", "contacts")], &[("id", TypeOid::String)]);
+        let rows = scan_rows(&mut fdw(), &contacts).unwrap();
+
+        assert!(rows.is_empty());
+        assert_eq!(host::requests().len(), 2);
+        assert!(host::messages().iter().any(|m| m.starts_with(
+            "warning: 2 consecutive empty pages which claim more results for object 'contacts'"
+        )));
+    }
+
+    #[test]
+    fn association_count_columns_count_results() {
+        let body = serde_json::json!({ "results": [
+            {
+                "id": "1",
+                "associations": { "contacts": { "results": [{"id": "11"}, {"id": "12"}] } },
+            },
+            { "id": "2" },
+        ] });
+        let rows = single_page(
+            &[("object", "deals"), ("associations", "contacts")],
+            &[("id", TypeOid::String), ("associations.contacts.count", TypeOid::I64)],
+            &body.to_string(),
+        )
+        .unwrap();
+        assert_eq!(text(&rows[0][1]).as_deref(), Some("2"));
+        assert_eq!(text(&rows[1][1]).as_deref(), Some("0"));
+    }
+
+    #[test]
+    fn dotted_paths_read_nested_address_values() {
+        let body = serde_json::json!({ "results": [
+            { "id": "1", "properties": {}, "address": { "city": "Berlin", "zip": 10115 } },
+            { "id": "2", "properties": {} },
+        ] });
+        let columns = [("address.city", TypeOid::String), ("address.zip", TypeOid::String)];
+        let rows = single_page(
+            &[("object", "companies"), ("on_conversion_error", "null")],
+            &columns,
+            &body.to_string(),
+        )
+        .unwrap();
+        assert_eq!(text(&rows[0][0]).as_deref(), Some("Berlin"));
+        assert_eq!(text(&rows[0][1]).as_deref(), Some("10115"));
+        assert!(rows[1][0].is_none() && rows[1][1].is_none());
+
+        let err = single_page(&[("object", "companies")], &columns, &body.to_string());
+        assert!(err.unwrap_err().ends_with("source column 'address.city' not found"));
+    }
+
+    #[test]
+    fn stable_order_splits_windows_at_the_same_points_across_scans() {
+        let sorted = scan(
+            &[("object", "contacts"), ("stable_order", "true")],
+            &[("id", TypeOid::String)],
+        );
+        let scan_windows = || {
+            host::respond(200, r#"{"total": 15000, "results": [{"id": "0"}]}"#);
+            host::respond(200, r#"{"total": 7000, "results": [{"id": "1"}]}"#);
+            host::respond(200, r#"{"total": 8000, "results": [{"id": "2"}]}"#);
+            let rows = scan_rows(&mut fdw(), &sorted).unwrap();
+            let ids: Vec<_> = rows.iter().filter_map(|row| text(&row[0])).collect();
+            let requests = host::requests();
+            let windows: Vec<_> = requests[requests.len() - 3..]
+                .iter()
+                .map(|req| request_window(req, "createdate"))
+                .collect();
+            (ids, windows)
+        };
+
+        let first = scan_windows();
+        host::advance(3_600_000);
+        let second = scan_windows();
+        assert_eq!(first, second);
+        assert_eq!(first.1[2].map(|(_, end)| end), Some(SEARCH_WINDOW_END_MS));
+    }
+
+    #[test]
+    fn conversion_warnings_are_capped_by_error_sample_limit() {
+        let record = |id: &str| serde_json::json!({ "id": id, "properties": { "amount": "lots" } });
+        let body = serde_json::json!({ "results": [record("1"), record("2"), record("3")] });
+        let rows = single_page(
+            &[("object", "deals"), ("on_conversion_error", "null"), ("error_sample_limit", "1")],
+            &[("id", TypeOid::String), ("amount", TypeOid::F64)],
+            &body.to_string(),
+        )
+        .unwrap();
+        assert_eq!(rows.len(), 3);
+
+        let warnings: Vec<_> = host::messages()
+            .into_iter()
+            .filter(|m| m.starts_with("warning: "))
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                "warning: cannot convert value \"lots\" to type TypeOid::F64 \
+                 (column 'amount' of object 'deals')",
+                "warning: ... 2 more conversion warnings suppressed",
+            ]
+        );
+    }
+
+    #[test]
+    fn in_quals_become_search_filters_kept_across_pages() {
+        let mut fdw = fdw();
+        let mut search = scan(
+            &[("object", "contacts"), ("qual_pushdown", "true")],
+            &[("id", TypeOid::String), ("email", TypeOid::String)],
+        );
+        let emails = ["a@example.com", "b@example.com"];
+        search.quals = vec![QualSpec {
+            field: "email".to_owned(),
+            operator: "=".to_owned(),
+            value: Value::Array(emails.iter().map(|e| Cell::String(e.to_string())).collect()),
+            use_or: true,
+            has_param: false,
+        }];
+        host::respond(
+            200,
+            r#"{"total": 2, "results": [{"id": "1", "properties": {"email": "a@example.com"}}],
+                "paging": {"next": {"after": "1"}}}"#,
+        );
+        host::respond(
+            200,
+            r#"{"total": 2, "results": [{"id": "2", "properties": {"email": "b@example.com"}}]}"#,
+        );
+        let rows = scan_rows(&mut fdw, &search).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert!(!fdw.can_list());
+
+        let requests = host::requests();
+        assert_eq!(requests.len(), 2);
+        let filter = serde_json::json!({
+            "propertyName": "email",
+            "operator": "IN",
+            "values": emails,
+        });
+        for (req, after) in requests.iter().zip([None, Some("1")]) {
+            assert!(req.url.ends_with("/crm/v3/objects/contacts/search"));
+            let body: JsonValue = serde_json::from_str(&req.body).unwrap();
+            assert_eq!(body["filterGroups"][0]["filters"], serde_json::json!([filter]));
+            assert_eq!(body["after"].as_str(), after);
+        }
+    }
+
+    #[test]
+    fn labeled_associations_fill_association_label() {
+        let mut fdw = fdw();
+        host::respond(
+            200,
+            r#"{"results": [{"id": "9", "properties": {}, "associations": {
+                "contacts": {"results": [{"id": "11"}, {"id": "12"}]}}}]}"#,
+        );
+        host::respond(
+            200,
+            r#"{"results": [{"from": {"id": "9"}, "to": [
+                {"toObjectId": 11, "associationTypes": [
+                    {"label": "Decision maker"}, {"label": "Champion"}, {"label": null}
+                ]},
+                {"toObjectId": 12, "associationTypes": [{"label": null}]}
+            ]}]}"#,
+        );
+        let labeled = scan(
+            &[("object", "deals"), ("unnest_associations", "contacts")],
+            &[("associated.id", TypeOid::String), ("association_label", TypeOid::String)],
+        );
+        let rows = scan_rows(&mut fdw, &labeled).unwrap();
+        assert_eq!(text(&rows[0][0]).as_deref(), Some("11"));
+        assert_eq!(text(&rows[0][1]).as_deref(), Some("Decision maker;Champion"));
+        assert_eq!(text(&rows[1][0]).as_deref(), Some("12"));
+        assert!(rows[1][1].is_none());
+
+        let req = host::requests().pop().unwrap();
+        assert!(req.url.ends_with("/crm/v4/associations/deals/contacts/batch/read"));
+        assert_eq!(req.body, r#"{"inputs":[{"id":"9"}]}"#);
+    }
+
+    #[test]
+    fn qual_free_scans_pick_list_despite_qual_pushdown() {
+        let mut fdw = fdw();
+        let mut pushdown = scan(
+            &[("object", "contacts"), ("qual_pushdown", "true")],
+            &[("id", TypeOid::String), ("email", TypeOid::String)],
+        );
+        host::respond(200, r#"{"results": []}"#);
+        scan_rows(&mut fdw, &pushdown).unwrap();
+        assert!(!fdw.use_search);
+
+        // a parameterized qual isn't known in time to push down either
+        pushdown.quals = vec![QualSpec {
+            field: "email".to_owned(),
+            operator: "=".to_owned(),
+            value: Value::Cell(Cell::String("a@example.com".to_owned())),
+            use_or: false,
+            has_param: true,
+        }];
+        host::respond(200, r#"{"results": []}"#);
+        scan_rows(&mut fdw, &pushdown).unwrap();
+        assert!(!fdw.use_search);
+
+        let requests = host::requests();
+        assert_eq!(requests.len(), 2);
+        for req in &requests {
+            assert!(matches!(req.method, http::Method::Get));
+            assert!(req.url.contains("/crm/v3/objects/contacts?"), "{}", req.url);
+        }
+    }
+
+    #[test]
+    fn millis_to_secs_emits_whole_seconds() {
+        let column = "hs_time_in_appointmentscheduled";
+        let rows = single_page(
+            &[("object", "deals"), ("hs_value_kind", &format!("{}:millis_to_secs", column))],
+            &[(column, TypeOid::I64)],
+            r#"{"results": [
+                {"id": "1", "properties": {"hs_time_in_appointmentscheduled": "65000"}},
+                {"id": "2", "properties": {"hs_time_in_appointmentscheduled": 65999}}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(text(&rows[0][0]).as_deref(), Some("65"));
+        assert_eq!(text(&rows[1][0]).as_deref(), Some("65"));
+    }
+
+    #[test]
+    fn raw_mode_returns_records_untransformed() {
+        let record = r#"{"id": "1", "properties": {"amount": 1500.00, "tags": "a;b"}}"#;
+        let body = format!(r#"{{"results": [{}], "paging": null}}"#, record);
+        let rows = single_page(
+            &[("object", "deals"), ("raw_mode", "true")],
+            &[("record", TypeOid::Json)],
+            &body,
+        )
+        .unwrap();
+        let expected: JsonValue = serde_json::from_str(record).unwrap();
+        assert_eq!(text(&rows[0][0]), Some(expected.to_string()));
+        assert!(text(&rows[0][0]).unwrap().contains("1500.00"));
+
+        let rows = single_page(
+            &[("object", "deals"), ("raw_mode", "page")],
+            &[("page", TypeOid::Json)],
+            &body,
+        )
+        .unwrap();
+        let page: JsonValue = serde_json::from_str(&text(&rows[0][0]).unwrap()).unwrap();
+        assert_eq!(page["results"][0], expected);
+
+        let err = single_page(
+            &[("object", "deals"), ("raw_mode", "true")],
+            &[("id", TypeOid::String)],
+            &body,
+        );
+        assert_eq!(err.unwrap_err(), "raw_mode column 'id' must be of type jsonb");
+    }
+
+    #[test]
+    fn gdpr_deletes_need_their_own_guard() {
+        let mut deletes = fdw();
+        let contacts = [("object", "contacts"), ("write_operations", "gdpr_delete")];
+        assert_eq!(
+            deletes.start_modify(&options(&contacts)),
+            Err("write operation 'gdpr_delete' permanently deletes contacts and requires the \
+                 option gdpr_delete 'true'"
+                .to_owned())
+        );
+        let companies = [("object", "companies"), contacts[1], ("gdpr_delete", "true")];
+        assert_eq!(
+            deletes.start_modify(&options(&companies)),
+            Err("GDPR deletes are only available for contacts, not 'companies'".to_owned())
+        );
+
+        deletes.start_modify(&options(&[contacts[0], contacts[1], ("gdpr_delete", "true")]))
+            .unwrap();
+        host::respond(204, "");
+        deletes.delete_row(&Cell::I64(42)).unwrap();
+        let req = host::requests().pop().unwrap();
+        assert!(matches!(req.method, http::Method::Post));
+        assert!(req.url.ends_with("/crm/v3/objects/contacts/gdpr-delete"));
+        assert_eq!(req.body, r#"{"objectId":"42"}"#);
+
+        let mut merges_only = fdw();
+        merges_only
+            .start_modify(&options(&[("object", "contacts"), ("write_operations", "merge")]))
+            .unwrap();
+        assert!(merges_only.delete_row(&Cell::I64(42)).is_err());
+        assert_eq!(host::requests().len(), 1);
+    }
+
+    #[test]
+    fn writes_do_not_retry_server_errors_which_reads_do() {
+        let mut fdw = fdw();
+        host::respond(500, r#"{"message": "oops"}"#);
+        host::respond(200, r#"{"results": []}"#);
+        scan_rows(&mut fdw, &scan(&[("object", "contacts")], &[])).unwrap();
+        assert_eq!(host::requests().len(), 2);
+
+        fdw.start_modify(&options(&[("object", "contacts"), ("write_operations", "merge")]))
+            .unwrap();
+        host::respond(500, r#"{"message": "oops"}"#);
+        host::respond(200, "{}");
+        let cells = [("_merge_into".to_owned(), Some(Cell::I64(1)))];
+        let err = fdw.update_row(&Cell::I64(2), &cells).unwrap_err();
+        assert!(err.starts_with("HubSpot API request failed with status 500"), "{}", err);
+        assert_eq!(host::requests().len(), 3);
+    }
+
+    #[test]
+    fn provenance_column_gathers_the_source_properties() {
+        let mut fdw = fdw();
+        host::respond(
+            200,
+            r#"{"results": [{"id": "1", "properties": {
+                "email": "a@example.com",
+                "hs_object_source": "INTEGRATION",
+                "hs_object_source_id": "1234",
+                "hs_object_source_label": "Zapier"
+            }}]}"#,
+        );
+        let provenance = scan(
+            &[("object", "contacts"), ("provenance_column", "origin")],
+            &[("email", TypeOid::String), ("origin", TypeOid::Json)],
+        );
+        let rows = scan_rows(&mut fdw, &provenance).unwrap();
+        let origin: JsonValue = serde_json::from_str(&text(&rows[0][1]).unwrap()).unwrap();
+        assert_eq!(
+            origin,
+            serde_json::json!({
+                "hs_object_source": "INTEGRATION",
+                "hs_object_source_id": "1234",
+                "hs_object_source_label": "Zapier",
+            })
+        );
+
+        let url = &host::requests()[0].url;
+        assert!(url.contains("properties=email,hs_object_source,hs_object_source_id,"), "{}", url);
+    }
+
+    #[test]
+    fn empty_scans_make_no_requests() {
+        let eq = |field: &str, value: &str, has_param: bool| QualSpec {
+            field: field.to_owned(),
+            operator: "=".to_owned(),
+            value: Value::Cell(Cell::String(value.to_owned())),
+            use_or: false,
+            has_param,
+        };
+        assert!(contradictory_quals(&[eq("email", "a", false), eq("email", "b", false)]));
+        assert!(!contradictory_quals(&[eq("email", "a", false), eq("email", "a", false)]));
+        assert!(!contradictory_quals(&[eq("email", "a", false), eq("name", "b", false)]));
+        // parameter values are only known at execution
+        assert!(!contradictory_quals(&[eq("email", "a", false), eq("email", "b", true)]));
+
+        let mut fdw = fdw();
+        let mut limited = scan(&[("object", "contacts")], &[("id", TypeOid::String)]);
+        limited.limit = Some(0);
+        assert!(scan_rows(&mut fdw, &limited).unwrap().is_empty());
+        let mut contradictory = scan(&[("object", "contacts")], &[("email", TypeOid::String)]);
+        contradictory.quals = vec![eq("email", "a", false), eq("email", "b", false)];
+        assert!(scan_rows(&mut fdw, &contradictory).unwrap().is_empty());
+
+        assert!(host::requests().is_empty());
+        let skipped = "info: Scan of contacts can't return any rows, skipping its requests";
+        assert!(host::messages().iter().any(|m| m == skipped));
+    }
+
+    #[test]
+    fn table_api_url_needs_the_server_to_allow_it() {
+        let proxied = scan(
+            &[("object", "contacts"), ("api_url", "https://proxy.example.com/")],
+            &[("id", TypeOid::String)],
+        );
+        let err = scan_rows(&mut fdw(), &proxied).unwrap_err();
+        assert_eq!(
+            err,
+            "table option 'api_url' requires the server option allow_table_api_url 'true'"
+        );
+        assert!(host::requests().is_empty());
+
+        // the override wins for data, while tokens are still refreshed
+        // against the server's api_url
+        let mut fdw = fdw_with(&[
+            ("allow_table_api_url", "true"),
+            ("refresh_token", "refresh"),
+            ("client_id", "client"),
+            ("client_secret", "secret"),
+        ]);
+        host::respond(200, r#"{"access_token": "fresh", "expires_in": 1800}"#);
+        host::respond(200, r#"{"results": []}"#);
+        scan_rows(&mut fdw, &proxied).unwrap();
+        let requests = host::requests();
+        assert_eq!(requests[0].url, "https://api.hubapi.com/oauth/v1/token");
+        assert!(requests[1].url.starts_with("https://proxy.example.com/crm/v3/objects/contacts?"));
+    }
+
+    #[test]
+    fn mixed_case_property_keys_resolve_to_lowercased_columns() {
+        let rows = single_page(
+            &[("object", "contacts"), ("lowercase_property_keys", "true")],
+            &[("firstname", TypeOid::String), ("custom_score", TypeOid::I64)],
+            r#"{"results": [{"id": "1", "properties": {"FirstName": "Ada", "Custom_Score": 3}}]}"#,
+        )
+        .unwrap();
+        assert_eq!(text(&rows[0][0]).as_deref(), Some("Ada"));
+        assert_eq!(text(&rows[0][1]).as_deref(), Some("3"));
+
+        // without the option the mixed-case keys aren't found
+        let err = single_page(
+            &[("object", "contacts")],
+            &[("firstname", TypeOid::String)],
+            r#"{"results": [{"id": "1", "properties": {"FirstName": "Ada"}}]}"#,
+        );
+        assert!(err.unwrap_err().ends_with("source column 'firstname' not found"));
+    }
+
+    #[test]
+    fn source_columns_give_the_newest_entry_source_type() {
+        let rows = single_page(
+            &[("object", "deals"), ("properties_with_history", "dealstage")],
+            &[("dealstage", TypeOid::String), ("dealstage_source", TypeOid::String)],
+            r#"{"results": [
+                {"id": "1", "properties": {"dealstage": "won"},
+                 "propertiesWithHistory": {"dealstage": [
+                    {"value": "won", "timestamp": "2023-03-01T00:00:00Z", "sourceType": "CRM_UI"},
+                    {"value": "new", "timestamp": "2023-01-01T00:00:00Z", "sourceType": "IMPORT"}
+                 ]}},
+                {"id": "2", "properties": {"dealstage": "new"}}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(text(&rows[0][1]).as_deref(), Some("CRM_UI"));
+        assert!(rows[1][1].is_none());
+        assert!(host::requests()[0].url.contains("propertiesWithHistory=dealstage"));
+    }
+
+    #[test]
+    fn conflicting_options_are_rejected() {
+        assert_eq!(
+            check_option_conflicts(&options(&[("raw_mode", "page"), ("emit_keyset", "true")])),
+            Err("options 'raw_mode' and 'emit_keyset' can't be combined: \
+                 raw rows aren't tracked by id"
+                .to_owned())
+        );
+        let history = [("id_only", "true"), ("properties_with_history", "dealstage")];
+        assert_eq!(
+            check_option_conflicts(&options(&history)),
+            Err("options 'id_only' and 'properties_with_history' can't be combined: \
+                 id_only scans fetch only ids"
+                .to_owned())
+        );
+        // options set to 'false' don't conflict
+        assert!(check_option_conflicts(&options(&[("raw_mode", "false"), ("id_only", "true")]))
+            .is_ok());
+
+        let err = single_page(
+            &[("object", "deals"), ("raw_mode", "true"), ("id_only", "true")],
+            &[("record", TypeOid::Json)],
+            r#"{"results": []}"#,
+        );
+        assert!(err.unwrap_err().starts_with("options 'raw_mode' and 'id_only' can't be combined"));
+        assert!(host::requests().is_empty());
+    }
+}